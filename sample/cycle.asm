@@ -0,0 +1,3 @@
+CYCLE	START	0
+	INCLUDE	'include/cycle_a.asm'
+	END	CYCLE
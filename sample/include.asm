@@ -0,0 +1,6 @@
+INCL	START	0
+	INCLUDE	'include/consts.asm'
+FIRST	+LDA	#MAXLEN
+	JSUB	WRITE
+	INCLUDE	'write.asm'
+	END	FIRST
@@ -0,0 +1,2 @@
+MAXLEN	EQU	4096
+DEVICE	EQU	5
@@ -0,0 +1,2 @@
+	LDA	#0
+	INCLUDE	'cycle_a.asm'
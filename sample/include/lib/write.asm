@@ -0,0 +1,4 @@
+WRITE	TD	OUTPUT
+	JEQ	WRITE
+	RSUB
+OUTPUT	BYTE	DEVICE
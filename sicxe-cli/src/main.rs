@@ -1,15 +1,78 @@
-use sicxe::assembler::assemble;
+use sicxe::assembler::{assemble_file_sections, optimize, AssemblerOptions};
+use sicxe::frame::charset::Charset;
+use sicxe::frameformer::literal::LiteralPlacement;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let filename = &args.get(1).expect("No filename given");
-
-    let source = std::fs::read_to_string(filename).expect("Failed to read file");
-    let obj = assemble(&source);
-    if let Err(e) = obj {
-        println!("{}", e);
-        return;
+    let mut args = std::env::args().skip(1);
+    let mut filename = None;
+    let mut options = AssemblerOptions::default();
+    let mut show_blocks = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-x" | "--instructions" => {
+                let path = args.next().expect("No instruction definition file given");
+                let definitions = std::fs::read_to_string(path)
+                    .expect("Failed to read instruction definition file");
+                if let Err(e) = options.optab.load_definitions(&definitions) {
+                    println!("{}", e);
+                    return;
+                }
+            }
+            "-I" | "--include-path" => {
+                let path = args.next().expect("No include path given");
+                options.include_paths.push(path.into());
+            }
+            "-D" | "--define" => {
+                let definition = args.next().expect("No symbol definition given");
+                if let Err(e) = options.define(&definition) {
+                    println!("{}", e);
+                    return;
+                }
+            }
+            "-a" | "--absolute" => options.absolute = true,
+            "-b" | "--blocks" => show_blocks = true,
+            "--literal-order" => {
+                let order = args.next().expect("No literal order given");
+                options.literal_placement = match order.as_str() {
+                    "first-use" => LiteralPlacement::FirstUse,
+                    "largest-first" => LiteralPlacement::LargestFirst,
+                    "name" => LiteralPlacement::ByName,
+                    _ => {
+                        println!("Unknown literal order \"{}\"", order);
+                        return;
+                    }
+                };
+            }
+            "--merge-literals" => options.merge_literals = true,
+            "--charset" => {
+                let charset = args.next().expect("No character set given");
+                options.charset = match charset.as_str() {
+                    "ascii" => Charset::Ascii,
+                    "ebcdic" => Charset::Ebcdic,
+                    _ => {
+                        println!("Unknown character set \"{}\"", charset);
+                        return;
+                    }
+                };
+            }
+            _ => filename = Some(arg),
+        }
     }
+    let filename = filename.expect("No filename given");
+
+    let sections = match assemble_file_sections(filename.as_ref(), &options) {
+        Ok(sections) => sections,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
 
-    println!("{}", obj.unwrap());
+    for section in sections {
+        // the block table goes to stderr to keep the object program intact
+        if show_blocks {
+            eprintln!("{}", section.blocks);
+        }
+        println!("{}", optimize(section.records));
+    }
 }
@@ -1,44 +1,139 @@
 use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 
+use crate::frame::charset::Charset;
+use crate::frame::expression::parse_constant;
+use crate::frame::instruction::register_number;
+use crate::frame::optab::OpTable;
 use crate::frame::record::ObjectRecord;
 use crate::frame::*;
-use crate::frameformer::block::rearrange_blocks;
-use crate::frameformer::literal::dump_literals;
+use crate::frameformer::block::BlockTable;
+use crate::frameformer::literal::{dump_literals, LiteralPlacement};
 use crate::frameformer::section::split_into_sections;
 use crate::frameformer::symbol::resolve_symbols;
 use crate::frameformer::translate::translate_to_record;
+use crate::preprocess::{read_source, SourceLine};
+
+/// Configures how a program is assembled.
+#[derive(Debug, Clone, Default)]
+pub struct AssemblerOptions {
+    /// The operation code table instructions are looked up in,
+    /// user-defined instructions can be merged into it with [`OpTable::load_definitions`].
+    pub optab: OpTable,
+    /// Assembles an absolute program, which is loaded at its starting address
+    /// and gets no modification records for relocation.
+    pub absolute: bool,
+    /// The order of the literals in a literal pool.
+    pub literal_placement: LiteralPlacement,
+    /// Lets literals producing the same bytes, e.g. `=X'454F46'` and `=C'EOF'`, share storage.
+    pub merge_literals: bool,
+    /// The character set `C'...'` constants are encoded in.
+    pub charset: Charset,
+    /// The directories searched for `INCLUDE` files not found next to the including file.
+    pub include_paths: Vec<PathBuf>,
+    /// Absolute symbols defined before the source, e.g. buffer sizes or device codes
+    /// chosen per build, which the source can only redefine with `SET`.
+    pub predefined: Vec<(String, i32)>,
+}
+
+impl AssemblerOptions {
+    /// Adds a predefined symbol from `NAME=VALUE`, or `NAME` alone for the value 1.
+    /// The value is a constant such as `4096`, `-1`, `X'F3'` or `0x1000`.
+    pub fn define(&mut self, definition: &str) -> Result<(), String> {
+        let (name, value) = definition.split_once('=').unwrap_or((definition, "1"));
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("Invalid symbol name \"{}\"", name));
+        }
+        if register_number(name).is_some() {
+            return Err(format!("Register {} cannot be predefined", name));
+        }
+        let value = match value.strip_prefix('-') {
+            Some(value) => -parse_constant(value)?,
+            None => parse_constant(value)?,
+        };
+
+        self.predefined.retain(|(n, _)| n != name);
+        self.predefined.push((name.to_string(), value));
+        Ok(())
+    }
+}
 
 pub fn assemble(source: &str) -> Result<String, String> {
+    assemble_with(source, &AssemblerOptions::default())
+}
+
+/// A control section assembled into object records.
+#[derive(Debug, Clone)]
+pub struct AssembledSection {
+    pub records: Vec<ObjectRecord>,
+    pub blocks: BlockTable,
+}
+
+pub fn assemble_with(source: &str, options: &AssemblerOptions) -> Result<String, String> {
+    let mut result = String::new();
+    for section in assemble_sections(source, options)? {
+        let optimized = optimize(section.records);
+        result.push_str(&format!("{}\n", optimized));
+    }
+
+    Ok(result)
+}
+
+/// Assembles the source into the object records and the block table of each control section.
+pub fn assemble_sections(
+    source: &str,
+    options: &AssemblerOptions,
+) -> Result<Vec<AssembledSection>, String> {
     #[cfg(debug_assertions)]
     dbg!(&source);
 
+    let lines = read_source(source, None, options)?;
+    assemble_lines(lines, options)
+}
+
+/// Assembles a source file, `INCLUDE` files are looked up relative to it.
+pub fn assemble_file_sections(
+    path: &Path,
+    options: &AssemblerOptions,
+) -> Result<Vec<AssembledSection>, String> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read file \"{}\": {}", path.display(), e))?;
+    let lines = read_source(&source, Some(path), options)?;
+    assemble_lines(lines, options)
+}
+
+fn assemble_lines(
+    lines: Vec<SourceLine>,
+    options: &AssemblerOptions,
+) -> Result<Vec<AssembledSection>, String> {
     let mut frames = Vec::<Frame>::new();
-    for (i, line) in source.lines().enumerate() {
-        let frame = Frame::from_source(line, i as u32 + 1)?;
+    for line in lines {
+        let frame =
+            Frame::from_source_at(&line.text, line.location, &options.optab, options.charset)?;
         if let Some(frame) = frame {
             frames.push(frame);
         }
     }
 
-    let mut result = String::new();
+    let mut sections = vec![];
 
     let programs = split_into_sections(frames);
     for program in programs {
-        let frames = rearrange_blocks(program);
-        let frames = dump_literals(frames);
-        let frames = resolve_symbols(frames)?;
-        let records = translate_to_record(frames)?;
+        let frames = dump_literals(program, options)?;
+        let (frames, blocks) = resolve_symbols(frames, options)?;
+        let records = translate_to_record(frames, &blocks, options)?;
 
         #[cfg(debug_assertions)]
         for record in &records {
             println!("{}", record);
         }
 
-        let optimized = optimize(records);
-        result.push_str(&format!("{}\n", optimized));
+        sections.push(AssembledSection { records, blocks });
     }
 
-    Ok(result)
+    Ok(sections)
 }
 
 pub fn optimize(records: Vec<ObjectRecord>) -> String {
@@ -99,9 +194,13 @@ pub fn optimize(records: Vec<ObjectRecord>) -> String {
     let texts = texts.collect::<Vec<_>>();
     let mut current_line = String::new();
     let mut current_start = 0;
+    let mut current_segment = 0;
     for r in texts {
         if let ObjectRecord::Text(r) = r {
-            if current_start + current_line.len() / 2 != r.start as usize {
+            // break at gaps and block switches
+            if current_start + current_line.len() / 2 != r.start as usize
+                || current_segment != r.segment
+            {
                 if !current_line.is_empty() {
                     let len = current_line.len() / 2;
                     optimized.push_str(&format!(
@@ -111,6 +210,7 @@ pub fn optimize(records: Vec<ObjectRecord>) -> String {
                 }
                 current_line = String::new();
                 current_start = r.start as usize;
+                current_segment = r.segment;
             }
 
             let mut data = VecDeque::from(r.data.clone());
@@ -200,26 +300,811 @@ mod tests {
         }
     }
 
-    // #[test]
-    // fn parse_code2() {
-    //     let source = fs::read_to_string("../sample/code2.asm").unwrap();
-    //     let result = assemble(&source).unwrap();
-    //     println!("{}", result);
+    #[test]
+    fn custom_instructions() {
+        let mut options = AssemblerOptions::default();
+        options
+            .optab
+            .load_definitions("HALT FC 3 none\nPUSH C4 2 r1")
+            .unwrap();
+
+        let source = "PROG\tSTART\t0\n\tPUSH\tA\n\tHALT\n\tEND\tPROG";
+        let result = assemble_with(source, &options).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000005\nT00000005C400FF0000\nE000000"
+        );
+
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("Invalid operator \"A\""), "{err}");
+
+        // an operation sharing the opcode of RSUB keeps its own operand signature
+        options.optab.load_definitions("JRET 4C 3 m").unwrap();
+        let source = "PROG\tSTART\t0\n\tJRET\tPROG\n\tRSUB\n\tEND\tPROG";
+        let result = assemble_with(source, &options).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000006\nT000000064F2FFD4F0000\nE000000"
+        );
+    }
+
+    #[test]
+    fn expressions() {
+        let source = "PROG\tSTART\t0\n\
+                      LOOP\tCLEAR\tA\n\
+                      \tJ\t*-2\n\
+                      BUFFER\tRESB\t(2+1)*2\n\
+                      BUFEND\tEQU\t*\n\
+                      LEN\tEQU\tBUFEND-BUFFER+-1\n\
+                      \tRESB\tLEN\n\
+                      \tJ\tLOOP+-(-2)\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000013\nT00000005B4003F2FFB\nT000010033F2FEF\nE000000"
+        );
+    }
+
+    #[test]
+    fn expression_types() {
+        let source = "PROG\tSTART\t0\n\
+                      MASK\tEQU\t0x0F0\n\
+                      \tLDA\t#MASK\n\
+                      \tLDA\tBUF\n\
+                      \tAND\tMASK\n\
+                      BUF\tRESB\t1\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00000000000A\nT000000090100F00320034300F0\nE000000"
+        );
+
+        let source = "PROG\tSTART\t0\n\tLDA\tBUF*2\nBUF\tRESB\t1\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.contains("relative terms cannot be multiplied or divided"));
+
+        let source = "PROG\tSTART\t0\n\tEXTREF\tEXT\n\tLDA\tEXT\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.contains("External reference requires format 4"));
+    }
+
+    #[test]
+    fn relocation() {
+        let source = "PROG\tSTART\t1000\n\
+                      FIRST\t+JSUB\tSUB\n\
+                      SUB\tWORD\tFIRST\n\
+                      NUM\tWORD\t5\n\
+                      \tEND\tFIRST";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00100000000A\nT0010000A4B101004001000000005\nM00100105\nM00100406\nE001000"
+        );
+
+        let options = AssemblerOptions {
+            absolute: true,
+            ..Default::default()
+        };
+        let result = assemble_with(source, &options).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00100000000A\nT0010000A4B101004001000000005\nE001000"
+        );
+    }
+
+    #[test]
+    fn forward_references() {
+        let source = "PROG\tSTART\t0\n\
+                      BUF\tRESB\tSIZE\n\
+                      TAB\tRESW\tCOUNT\n\
+                      \tLDA\tTAB\n\
+                      SIZE\tEQU\tCOUNT*2\n\
+                      COUNT\tEQU\t4\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000017\nT00001403032FF1\nE000000"
+        );
+
+        // the size of BUF depends on its own location
+        let source = "PROG\tSTART\t0\n\
+                      BUF\tRESB\tSIZE\n\
+                      LAST\tEQU\t*\n\
+                      SIZE\tEQU\tLAST-BUF\n\
+                      \tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("Cannot resolve \"SIZE\"\n\tat"), "{}", err);
+    }
 
-    //     let fixture = fs::read_to_string("../sample/code2.obj").unwrap();
+    #[test]
+    fn unresolved_symbols() {
+        let source = "PROG\tSTART\t0\n\
+                      \tEXTDEF\tGONE\n\
+                      FOO\tEQU\tBAR\n\
+                      BAR\tEQU\tFOO+1\n\
+                      \tLDA\tMISSING\n\
+                      \tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(
+            err.lines().collect::<Vec<_>>(),
+            vec![
+                "Undefined symbol GONE",
+                "\tat Source \tEXTDEF\tGONE (Line 2)",
+                "Undefined symbol MISSING",
+                "\tat Source \tLDA\tMISSING (Line 5)",
+                "Circular reference FOO -> BAR -> FOO",
+                "\tat Source FOO\tEQU\tBAR (Line 3)",
+                "\tat Source BAR\tEQU\tFOO+1 (Line 4)",
+            ]
+        );
+    }
+
+    #[test]
+    fn org_restore() {
+        let source = "PROG\tSTART\t0\n\
+                      TABLE\tRESB\t9\n\
+                      \tORG\tTABLE\n\
+                      SYM\tRESB\t6\n\
+                      VAL\tRESW\t1\n\
+                      \tORG\n\
+                      \tLDA\tVAL\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00000000000C\nT00000903032FFA\nE000000"
+        );
+
+        // a backward reference still unresolved in the first pass
+        let source = "PROG\tSTART\t0\n\
+                      BUF\tRESB\tLEN\n\
+                      MARK\tWORD\t1\n\
+                      \tORG\tMARK\n\
+                      \tWORD\t2\n\
+                      \tORG\n\
+                      LEN\tEQU\t3\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000006\nT00000303000001\nT00000303000002\nE000000"
+        );
+
+        let source = "PROG\tSTART\t0\n\tORG\tLATER\nLATER\tRESB\t1\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("Forward reference to LATER in ORG operand"));
+
+        let source = "PROG\tSTART\t0\n\tORG\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("No location counter to restore by ORG"));
+
+        let source = "PROG\tSTART\t100\n\tORG\t50\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("ORG moves the location counter below the section start 0100"));
+    }
 
-    //     // trim
-    //     let result = result.trim();
-    //     let fixture = fixture.trim();
+    #[test]
+    fn program_blocks() {
+        let source = "PROG\tSTART\t0\n\
+                      \tLDA\tDATA\n\
+                      \tUSE\tB\n\
+                      DATA\tWORD\t5\n\
+                      \tUSE\n\
+                      \tJ\t*\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000009\n\
+             T00000003032003\n\
+             T00000603000005\n\
+             T000003033F2FFD\n\
+             E000000"
+        );
+
+        let sections = assemble_sections(source, &AssemblerOptions::default()).unwrap();
+        let blocks = sections[0]
+            .blocks
+            .iter()
+            .map(|b| (b.name.as_str(), b.number, b.start, b.length))
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, vec![("", 0, 0, 6), ("B", 1, 6, 3)]);
+
+        // ORG cannot move into another block
+        let source = "P\tSTART\t0\n\
+                      A\tWORD\t1\n\
+                      \tUSE\tB\n\
+                      C\tWORD\t2\n\
+                      \tORG\tA\n\
+                      D\tWORD\t3\n\
+                      \tORG\n\
+                      \tEND\tP";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(
+            err,
+            "ORG operand A is in block (default), not in the current block B\n\
+             \tat Source \tORG\tA (Line 5)"
+        );
+
+        let source = source.replace("\tORG\tA\n", "\tORG\t1\n");
+        let err = assemble(&source).unwrap_err();
+        assert!(err.starts_with("ORG moves the location counter before block B\n\tat"));
+
+        // ORG in one block is restored in the same block
+        let source = "PROG\tSTART\t0\n\
+                      T\tRESW\t2\n\
+                      \tORG\tT\n\
+                      \tUSE\tCDATA\n\
+                      \tWORD\t3\n\
+                      \tORG\n\
+                      \tWORD\t4\n\
+                      \tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(
+            err,
+            "ORG cannot restore the location counter of block (default) in block CDATA\n\
+             \tat Source \tORG (Line 6)"
+        );
+
+        let source = "PROG\tSTART\t0\n\
+                      T\tRESW\t2\n\
+                      \tORG\tT\n\
+                      \tUSE\tCDATA\n\
+                      \tWORD\t3\n\
+                      \tUSE\n\
+                      \tORG\n\
+                      \tWORD\t4\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00000000000C\n\
+             T00000903000003\n\
+             T00000603000004\n\
+             E000000"
+        );
+    }
+
+    #[test]
+    fn literal_kinds() {
+        let source = "PROG\tSTART\t0\n\
+                      \tLDA\t=5\n\
+                      \tLDA\t=W'5'\n\
+                      \tLDA\t=*\n\
+                      \tLDA\t=*\n\
+                      PTR\tWORD\t=C'EOF'\n\
+                      \tLTORG\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00000000001E\n\
+             T0000001E03200C03200C03200C03200C00001B000005000005000006000009454F46\n\
+             M00000C06\n\
+             M00001506\n\
+             M00001806\n\
+             E000000"
+        );
+
+        let source = "PROG\tSTART\t0\nL\tCLEAR\t=X'05'\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("Literals cannot be used as Format 2 operands"));
+
+        let source = "PROG\tSTART\t0\n\tRESB\t=5\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("Literals are not allowed here"));
+
+        let source = "PROG\tSTART\t0\n\tLDA\t=W'16777216'\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("Word literal \"W'16777216'\" out of range"));
+    }
+
+    #[test]
+    fn byte_constants() {
+        let source = "PROG\tSTART\t0\n\
+                      MSG\tBYTE\tC'IT''S\\n'\n\
+                      \tLDA\t=C'\\x00\\t'\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00000000000A\n\
+             T0000000A495427530A0320000009\n\
+             E000000"
+        );
+
+        let source = "PROG\tSTART\t0\nBUF\tBYTE\tX'ZZ'\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(
+            err,
+            "Invalid hexadecimal digit 'Z' in X'ZZ'\n\tat Source BUF\tBYTE\tX'ZZ' (Line 2)"
+        );
+
+        let source = "PROG\tSTART\t0\n\tLDA\t=C'\\q'\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("Invalid escape sequence \\q in C'\\q'\n\tat"));
+    }
 
-    //     // compare line by line
-    //     let result = result.lines().collect::<Vec<_>>();
-    //     let fixture = fixture.lines().collect::<Vec<_>>();
-    //     assert_eq!(result.len(), fixture.len());
-    //     for (i, (result, fixture)) in result.iter().zip(fixture.iter()).enumerate() {
-    //         assert_eq!(result, fixture, "line {}", i + 1);
-    //     }
-    // }
+    #[test]
+    fn data_lists() {
+        let source = "PROG\tSTART\t0\n\
+                      TAB\tWORD\t1,TAB+3,=*\n\
+                      MSG\tBYTE\tX'01',C'A,B',255\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000011\n\
+             T0000001100000100000300000E01412C42FF000000\n\
+             M00000306\n\
+             M00000606\n\
+             M00000E06\n\
+             E000000"
+        );
+    }
+
+    #[test]
+    fn byte_expressions() {
+        let source = "PROG\tSTART\t0\n\
+                      \tEXTREF\tDEV\n\
+                      LEN\tEQU\t10\n\
+                      BUF\tBYTE\t10,LEN-1,DEV,TAIL-BUF\n\
+                      TAIL\tBYTE\tX'FF',-1\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000006\n\
+             RDEV   \n\
+             T000000060A090004FFFF\n\
+             M00000202+DEV\n\
+             E000000"
+        );
+
+        let source = "PROG\tSTART\t0\n\
+                      \tBYTE\tBIG\n\
+                      BIG\tEQU\t300\n\
+                      \tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("Byte value 300 out of range\n\tat"));
+
+        // relative addresses only fit in a byte of an absolute program
+        let source = "PROG\tSTART\t0\n\
+                      BUF\tBYTE\tBUF+1\n\
+                      \tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(
+            err,
+            "Byte value 1 is a relative address and cannot be relocated\n\
+             \tat Source BUF\tBYTE\tBUF+1 (Line 2)"
+        );
+        let options = AssemblerOptions {
+            absolute: true,
+            ..Default::default()
+        };
+        let result = assemble_with(source, &options).unwrap();
+        assert_eq!(result.trim(), "HPROG  000000000001\nT0000000101\nE000000");
+    }
+
+    #[test]
+    fn include_files() {
+        let options = AssemblerOptions {
+            include_paths: vec!["../sample/include/lib".into()],
+            ..Default::default()
+        };
+        let sections = assemble_file_sections(Path::new("../sample/include.asm"), &options);
+        let result = optimize(sections.unwrap().remove(0).records);
+        assert_eq!(
+            result.trim(),
+            "HINCL  000000000011\n\
+             T00000011011010004B2000E32006332FFA4F000005\n\
+             E000000"
+        );
+
+        let err = assemble_file_sections(Path::new("../sample/include.asm"), &Default::default())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "Cannot find include file \"write.asm\"\n\
+             \tat Source \tINCLUDE\t'write.asm' (../sample/include.asm:5)"
+        );
+
+        let err = assemble_file_sections(Path::new("../sample/cycle.asm"), &options).unwrap_err();
+        assert_eq!(
+            err,
+            "Circular include of \"cycle_a.asm\"\n\
+             \tat Source \tINCLUDE\t'cycle_a.asm' (../sample/include/cycle_b.asm:2)\n\
+             \tincluded from ../sample/include/cycle_a.asm:1\n\
+             \tincluded from ../sample/cycle.asm:2"
+        );
+
+        let source = "PROG\tSTART\t0\nLBL\tINCLUDE\t'x.asm'\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("INCLUDE cannot have a label\n\tat"));
+
+        // only INCLUDE lines that are assembled are looked up
+        let source = "PROG\tSTART\t0\n\
+                      DEBUG\tEQU\t0\n\
+                      \tIF\tDEBUG\n\
+                      \tINCLUDE\t'missing.asm'\n\
+                      \tENDIF\n\
+                      \tRSUB\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000003\nT000000034F0000\nE000000"
+        );
+        let err = assemble(&source.replace("EQU\t0", "EQU\t1")).unwrap_err();
+        assert_eq!(
+            err,
+            "Cannot find include file \"missing.asm\"\n\
+             \tat Source \tINCLUDE\t'missing.asm' (Line 4)"
+        );
+
+        // INCLUDE as a symbol, in the operand and the label field
+        let source = "PROG\tSTART\t0\n\tJ\tINCLUDE\nINCLUDE\tRSUB\n\tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000006\nT000000063F20004F0000\nE000000"
+        );
+    }
+
+    #[test]
+    fn macros() {
+        let source = "PROG\tSTART\t0\n\
+                      WAIT\tMACRO\t&DEV=05\n\
+                      $LOOP\tTD\t=X'&DEV'\n\
+                      \tJEQ\t$LOOP\n\
+                      \tMEND\n\
+                      FIRST\tWAIT\n\
+                      \tWAIT\t&DEV=F1\n\
+                      \tEND\tFIRST";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00000000000E\n\
+             T0000000EE32009332FFAE32004332FFA05F1\n\
+             E000000"
+        );
+
+        let source = "PROG\tSTART\t0\n\
+                      LOAD\tMACRO\t&X\n\
+                      \tLDA\t&X\n\
+                      \tMEND\n\
+                      \tLOAD\tMISSING\n\
+                      \tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(
+            err,
+            "Undefined symbol MISSING\n\
+             \tat Source \tLDA\tMISSING (Line 3)\n\
+             \texpanded from LOAD at Line 5"
+        );
+    }
+
+    #[test]
+    fn conditional_assembly() {
+        let source = "PROG\tSTART\t0\n\
+                      DEBUG\tEQU\t1\n\
+                      MODE\tEQU\tDEBUG+1\n\
+                      N\tSET\t1\n\
+                      \tIF\tDEBUG\n\
+                      \tIF\tMODE<>2\n\
+                      \tWORD\t99\n\
+                      \tELSE\n\
+                      \tWORD\tN\n\
+                      N\tSET\tN+1\n\
+                      \tENDIF\n\
+                      \tELSE\n\
+                      \tWORD\t98\n\
+                      \tENDIF\n\
+                      \tWORD\tN\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000006\n\
+             T00000006000001000002\n\
+             E000000"
+        );
+
+        let source = "PROG\tSTART\t0\n\tIF\t1\n\tWORD\t1\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(err, "IF without ENDIF\n\tat Source \tIF\t1 (Line 2)");
+
+        let source = "PROG\tSTART\t0\n\tELSE\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("ELSE without IF\n\tat"));
+
+        let source = "PROG\tSTART\t0\n\tIF\tLATER\nLATER\tEQU\t1\n\tENDIF\n\tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert!(err.starts_with("Cannot evaluate \"LATER\" at assembly time\n\tat"));
+    }
+
+    #[test]
+    fn repetitions() {
+        let source = "PROG\tSTART\t0\n\
+                      \tREPT\t3,&I\n\
+                      \tBYTE\t&I*&I\n\
+                      \tENDR\n\
+                      \tIRP\t&L,<T0,T1>\n\
+                      \tWORD\t&L\n\
+                      \tENDR\n\
+                      T0\tEQU\t*\n\
+                      T1\tEQU\t16\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000009\n\
+             T00000009000104000009000010\n\
+             M00000306\n\
+             E000000"
+        );
+
+        let source = "PROG\tSTART\t0\n\
+                      \tREPT\t20,&I\n\
+                      B&I\tBYTE\t&I*16\n\
+                      \tENDR\n\
+                      \tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(
+            err,
+            "Byte value 256 out of range\n\
+             \tat Source B16\tBYTE\t16*16 (Line 3)\n\
+             \texpanded from REPT iteration 17 at Line 2"
+        );
+    }
+
+    #[test]
+    fn ebcdic_constants() {
+        let source = "PROG\tSTART\t0\n\
+                      \tLDA\t=C'EOF'\n\
+                      MSG\tBYTE\tC'A1 \\x41'\n\
+                      HEX\tBYTE\tX'41'\n\
+                      \tEND\tPROG";
+        let options = AssemblerOptions {
+            charset: Charset::Ebcdic,
+            ..Default::default()
+        };
+        let result = assemble_with(source, &options).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00000000000B\n\
+             T0000000B032005C1F1404141C5D6C6\n\
+             E000000"
+        );
+
+        // character constants in expressions use the same character set
+        let source = "PROG\tSTART\t0\n\
+                      \tLDA\t#C'A'\n\
+                      K\tEQU\tC'A'\n\
+                      \tBYTE\tC'A'\n\
+                      \tWORD\tK\n\
+                      \tIF\tK=X'C1'\n\
+                      \tBYTE\tK\n\
+                      \tENDIF\n\
+                      \tEND\tPROG";
+        let result = assemble_with(source, &options).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000008\n\
+             T000000080100C1C10000C1C1\n\
+             E000000"
+        );
+    }
+
+    #[test]
+    fn predefined_symbols() {
+        let mut options = AssemblerOptions::default();
+        options.define("BUFSIZE=X'10'").unwrap();
+        options.define("DEVICE=-1").unwrap();
+        options.define("DEBUG").unwrap();
+        assert_eq!(
+            options.predefined,
+            vec![
+                ("BUFSIZE".to_string(), 16),
+                ("DEVICE".to_string(), -1),
+                ("DEBUG".to_string(), 1)
+            ]
+        );
+        assert!(options.clone().define("1ST=1").is_err());
+        assert!(options.clone().define("X=1").is_err());
+        assert!(options.clone().define("N=abc").is_err());
+
+        let source = "PROG\tSTART\t0\n\
+                      \tWORD\tBUFSIZE,DEVICE\n\
+                      \tIF\tDEBUG\n\
+                      BUF\tRESB\tBUFSIZE\n\
+                      \tENDIF\n\
+                      \tEND\tPROG";
+        let result = assemble_with(source, &options).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000016\n\
+             T00000006000010FFFFFF\n\
+             E000000"
+        );
+
+        let source = "PROG\tSTART\t0\n\
+                      BUFSIZE\tSET\tBUFSIZE*2\n\
+                      \tWORD\tBUFSIZE\n\
+                      \tEND\tPROG";
+        let result = assemble_with(source, &options).unwrap();
+        assert!(result.contains("T00000003000020"));
+
+        let source = "PROG\tSTART\t0\n\
+                      BUFSIZE\tEQU\t32\n\
+                      \tEND\tPROG";
+        let err = assemble_with(source, &options).unwrap_err();
+        assert_eq!(
+            err,
+            "Symbol BUFSIZE is predefined and cannot be redefined\n\
+             \tat Source BUFSIZE\tEQU\t32 (Line 2)"
+        );
+
+        let source = "PROG\tSTART\t0\n\
+                      DEVICE\tRESW\t1\n\
+                      \tEND\tPROG";
+        let err = assemble_with(source, &options).unwrap_err();
+        assert!(err.starts_with("Symbol DEVICE is predefined and cannot be redefined"));
+    }
+
+    #[test]
+    fn local_labels() {
+        let source = "PROG\tSTART\t0\n\
+                      READ\tCLEAR\tX\n\
+                      ?LOOP\tTIXR\tT\n\
+                      \tJLT\t?LOOP\n\
+                      \tJ\t?DONE\n\
+                      ?DONE\tRSUB\n\
+                      WRITE\tCLEAR\tX\n\
+                      ?LOOP\tTIXR\tT\n\
+                      \tJLT\t?LOOP\n\
+                      \tRSUB\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000017\n\
+             T00000017B410B8503B2FFB3F20004F0000B410B8503B2FFB4F0000\n\
+             E000000"
+        );
+
+        // macro expansions stay in the routine, a labeled call starts one
+        let source = "PROG\tSTART\t0\n\
+                      WAIT\tMACRO\t&DEV\n\
+                      $L\tTD\t=X'&DEV'\n\
+                      \tJEQ\t$L\n\
+                      \tMEND\n\
+                      READ\tCLEAR\tX\n\
+                      ?DONE\tRSUB\n\
+                      \tWAIT\tF1\n\
+                      \tJ\t?DONE\n\
+                      WRITE\tWAIT\t05\n\
+                      ?DONE\tRSUB\n\
+                      \tJ\t?DONE\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00000000001C\n\
+             T0000001CB4104F0000E32012332FFA3F2FF4E3200A332FFA4F00003F2FFAF105\n\
+             E000000"
+        );
+
+        let source = "PROG\tSTART\t0\n\
+                      READ\tRSUB\n\
+                      ?DONE\tRSUB\n\
+                      WRITE\tJ\t?DONE\n\
+                      \tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(
+            err,
+            "Undefined symbol WRITE?DONE\n\tat Source WRITE\tJ\t?DONE (Line 4)"
+        );
+
+        // the label generated for a =* literal stays in the scope of READ
+        let source = "PROG\tSTART\t0\n\
+                      READ\tCLEAR\tA\n\
+                      \tJ\t?L\n\
+                      \tLDA\t=*\n\
+                      ?L\tRSUB\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  00000000000E\n\
+             T0000000EB4003F20030320034F0000000005\n\
+             M00000B06\n\
+             E000000"
+        );
+
+        let source = "PROG\tSTART\t0\n\
+                      \tEXTDEF\tREAD,?DONE\n\
+                      READ\tRSUB\n\
+                      ?DONE\tRSUB\n\
+                      \tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(
+            err,
+            "Local label ?DONE cannot be exported\n\tat Source \tEXTDEF\tREAD,?DONE (Line 2)"
+        );
+    }
+
+    #[test]
+    fn symbolic_counts() {
+        let source = "PROG\tSTART\t0\n\
+                      \tSHIFTL\tA,N\n\
+                      \tSVC\tN-1\n\
+                      N\tEQU\t5\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  000000000004\n\
+             T00000004A404B040\n\
+             E000000"
+        );
+
+        let source = "PROG\tSTART\t0\n\
+                      N\tEQU\t5\n\
+                      \tSHIFTR\tT,N*4\n\
+                      \tEND\tPROG";
+        let err = assemble(source).unwrap_err();
+        assert_eq!(
+            err,
+            "Number 20 out of range 1..16\n\tat Source \tSHIFTR\tT,N*4 (Line 3)"
+        );
+
+        let err = assemble("PROG\tSTART\t0\n\tSVC\t16\n\tEND\tPROG").unwrap_err();
+        assert_eq!(
+            err,
+            "Number 16 out of range 0..15\n\tat Source \tSVC\t16 (Line 2)"
+        );
+    }
+
+    #[test]
+    fn constants() {
+        let source = "PROG\tSTART\t0\n\
+                      MASK\tEQU\t0x0F0\n\
+                      \tLDA\t#X'FF'\n\
+                      \tLDCH\t#C'A'\n\
+                      \tCOMP\t#1FH\n\
+                      BUF\tRESB\tMASK\n\
+                      \tEND\tPROG";
+        let result = assemble(source).unwrap();
+        assert_eq!(
+            result.trim(),
+            "HPROG  0000000000F9\nT000000090100FF51004129001F\nE000000"
+        );
+    }
+
+    #[test]
+    fn parse_code2() {
+        let source = fs::read_to_string("../sample/code2.asm").unwrap();
+        let result = assemble(&source).unwrap();
+        println!("{}", result);
+
+        let fixture = fs::read_to_string("../sample/code2.obj").unwrap();
+
+        // trim
+        let result = result.trim();
+        let fixture = fixture.trim();
+
+        // compare line by line
+        let result = result.lines().collect::<Vec<_>>();
+        let fixture = fixture.lines().collect::<Vec<_>>();
+        assert_eq!(result.len(), fixture.len());
+        for (i, (result, fixture)) in result.iter().zip(fixture.iter()).enumerate() {
+            assert_eq!(result, fixture, "line {}", i + 1);
+        }
+    }
 
     #[test]
     fn parse_code3() {
@@ -1,5 +1,6 @@
 use std::fmt::Display;
 
+use super::charset::Charset;
 use super::expression::*;
 use super::FrameLike;
 
@@ -36,11 +37,13 @@ pub struct END {
 #[derive(Debug, Clone, PartialEq)]
 pub struct BYTE {
     pub data: Vec<u8>,
+    /// Expressions filling single bytes of the data, by their offsets.
+    pub values: Vec<(usize, Expression)>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct WORD {
-    pub word: Box<Expression>,
+    pub words: Vec<Expression>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -55,7 +58,8 @@ pub struct RESW {
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ORG {
-    pub address: Box<Expression>,
+    /// The new locctr, or None to restore the locctr saved by the previous ORG.
+    pub address: Option<Box<Expression>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -94,39 +98,73 @@ pub struct EXTDEF {
     pub names: Vec<String>,
 }
 
-// Implement FrameLike for Directive
-impl FrameLike for Directive {
-    fn size(&self) -> Option<i32> {
-        match self {
-            Directive::START(_) => Some(0),
-            Directive::END(_) => Some(0),
-            Directive::BYTE(d) => Some(d.data.len() as i32),
-            Directive::WORD(_) => Some(3),
-            Directive::RESB(d) => d.bytes.eval(),
-            Directive::RESW(d) => {
-                let words = d.words.eval();
-                words.map(|words| words * 3)
+impl BYTE {
+    /// Appends an element of a BYTE list, either a constant or an expression taking a single byte.
+    /// Expressions that cannot be evaluated yet are kept to be filled in later.
+    fn push_element(&mut self, element: &str, charset: Charset) -> Result<(), String> {
+        if element.is_empty() {
+            return Err("Missing element in list".to_string());
+        }
+        if is_quoted_constant(element) {
+            self.data.extend(literal_to_data(element, charset)?);
+            return Ok(());
+        }
+
+        let value = parse_with(element, charset)?;
+        match value.resolve()? {
+            Some((value, ExpressionType::Absolute)) => self.data.push(byte_value(value)?),
+            _ => {
+                self.values.push((self.data.len(), value));
+                self.data.push(0);
             }
-            Directive::ORG(_) => Some(0),
-            Directive::BASE(_) => Some(0),
-            Directive::NOBASE(_) => Some(0),
-            Directive::EQU(_) => Some(0),
-            Directive::LTORG(_) => Some(0),
-            Directive::USE(_) => Some(0),
-            Directive::CSECT(_) => Some(0),
-            Directive::EXTREF(_) => Some(0),
-            Directive::EXTDEF(_) => Some(0),
         }
+        Ok(())
+    }
+}
+
+/// Checks that a value fits in a single byte, signed or unsigned.
+pub fn byte_value(value: i32) -> Result<u8, String> {
+    if !(-0x80..=0xFF).contains(&value) {
+        return Err(format!("Byte value {} out of range", value));
     }
+    Ok(value as u8)
+}
 
-    fn parse(
+/// Whether the element is a single `C'...'`, `X'...'` or `F'...'` constant
+/// rather than an expression.
+fn is_quoted_constant(element: &str) -> bool {
+    let Some(rest) = element
+        .strip_prefix(['C', 'X', 'F'])
+        .and_then(|rest| rest.strip_prefix('\''))
+    else {
+        return false;
+    };
+
+    let mut chars = rest.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\'' if chars.peek().is_some_and(|(_, c)| *c == '\'') => {
+                chars.next();
+            }
+            '\'' => return i == rest.len() - 1,
+            _ => {}
+        }
+    }
+    false
+}
+
+impl Directive {
+    /// Parses a directive, encoding character constants in the given character set.
+    /// If the operator is not a directive, return None.
+    pub fn parse_with(
+        charset: Charset,
         operator: &str,
         operand: Option<&str>,
         label: Option<&str>,
-    ) -> Option<Result<Self, String>>
-    where
-        Self: Sized,
-    {
+    ) -> Option<Result<Directive, String>> {
         match operator {
             "START" => {
                 if label.is_none() {
@@ -153,7 +191,7 @@ impl FrameLike for Directive {
                 }
                 let operand = operand.unwrap();
 
-                let first = parse(operand).ok()?;
+                let first = parse_with(operand, charset).ok()?;
                 let first = Box::new(first);
                 Some(Ok(Directive::END(END { first })))
             }
@@ -162,8 +200,17 @@ impl FrameLike for Directive {
                     return Some(Err("Missing operand".to_string()));
                 }
                 let operand = operand.unwrap();
-                let data = literal_to_data(operand).ok()?;
-                Some(Ok(Directive::BYTE(BYTE { data })))
+
+                let mut byte = BYTE {
+                    data: vec![],
+                    values: vec![],
+                };
+                for element in split_list(operand) {
+                    if let Err(e) = byte.push_element(element, charset) {
+                        return Some(Err(e));
+                    }
+                }
+                Some(Ok(Directive::BYTE(byte)))
             }
             "WORD" => {
                 if operand.is_none() {
@@ -171,9 +218,11 @@ impl FrameLike for Directive {
                 }
                 let operand = operand.unwrap();
 
-                let word = parse(operand).ok()?;
-                let word = Box::new(word);
-                Some(Ok(Directive::WORD(WORD { word })))
+                let words = split_list(operand).into_iter().map(parse).collect();
+                match words {
+                    Ok(words) => Some(Ok(Directive::WORD(WORD { words }))),
+                    Err(e) => Some(Err(e)),
+                }
             }
             "RESB" => {
                 if operand.is_none() {
@@ -181,7 +230,7 @@ impl FrameLike for Directive {
                 }
                 let operand = operand.unwrap();
 
-                let bytes = parse(operand).ok()?;
+                let bytes = parse_with(operand, charset).ok()?;
                 let bytes = Box::new(bytes);
                 Some(Ok(Directive::RESB(RESB { bytes })))
             }
@@ -191,18 +240,15 @@ impl FrameLike for Directive {
                 }
                 let operand = operand.unwrap();
 
-                let words = parse(operand).ok()?;
+                let words = parse_with(operand, charset).ok()?;
                 let words = Box::new(words);
                 Some(Ok(Directive::RESW(RESW { words })))
             }
             "ORG" => {
-                if operand.is_none() {
-                    return Some(Err("Missing operand".to_string()));
-                }
-                let operand = operand.unwrap();
-
-                let address = parse(operand).ok()?;
-                let address = Box::new(address);
+                let address = match operand {
+                    Some(operand) => Some(Box::new(parse_with(operand, charset).ok()?)),
+                    None => None,
+                };
                 Some(Ok(Directive::ORG(ORG { address })))
             }
             "BASE" => {
@@ -211,7 +257,7 @@ impl FrameLike for Directive {
                 }
                 let operand = operand.unwrap();
 
-                let address = parse(operand).ok()?;
+                let address = parse_with(operand, charset).ok()?;
                 let address = Box::new(address);
                 Some(Ok(Directive::BASE(BASE { address })))
             }
@@ -230,7 +276,7 @@ impl FrameLike for Directive {
                 }
                 let operand = operand.unwrap();
 
-                let value = parse(operand).ok()?;
+                let value = parse_with(operand, charset).ok()?;
                 let value = Box::new(value);
                 Some(Ok(Directive::EQU(EQU { value })))
             }
@@ -280,6 +326,71 @@ impl FrameLike for Directive {
     }
 }
 
+// Implement FrameLike for Directive
+impl FrameLike for Directive {
+    fn size(&self) -> Option<i32> {
+        match self {
+            Directive::START(_) => Some(0),
+            Directive::END(_) => Some(0),
+            Directive::BYTE(d) => Some(d.data.len() as i32),
+            Directive::WORD(d) => Some(3 * d.words.len() as i32),
+            Directive::RESB(d) => d.bytes.eval(),
+            Directive::RESW(d) => {
+                let words = d.words.eval();
+                words.map(|words| words * 3)
+            }
+            Directive::ORG(_) => Some(0),
+            Directive::BASE(_) => Some(0),
+            Directive::NOBASE(_) => Some(0),
+            Directive::EQU(_) => Some(0),
+            Directive::LTORG(_) => Some(0),
+            Directive::USE(_) => Some(0),
+            Directive::CSECT(_) => Some(0),
+            Directive::EXTREF(_) => Some(0),
+            Directive::EXTDEF(_) => Some(0),
+        }
+    }
+
+    fn parse(
+        operator: &str,
+        operand: Option<&str>,
+        label: Option<&str>,
+    ) -> Option<Result<Self, String>>
+    where
+        Self: Sized,
+    {
+        Directive::parse_with(Charset::default(), operator, operand, label)
+    }
+
+    fn expressions(&self) -> Option<Vec<&Expression>> {
+        match self {
+            Directive::END(d) => Some(vec![&d.first]),
+            Directive::BYTE(d) => Some(d.values.iter().map(|(_, v)| v).collect()),
+            Directive::WORD(d) => Some(d.words.iter().collect()),
+            Directive::RESB(d) => Some(vec![&d.bytes]),
+            Directive::RESW(d) => Some(vec![&d.words]),
+            Directive::ORG(d) => Some(d.address.iter().map(|a| &**a).collect()),
+            Directive::BASE(d) => Some(vec![&d.address]),
+            Directive::EQU(d) => Some(vec![&d.value]),
+            _ => None,
+        }
+    }
+
+    fn expressions_mut(&mut self) -> Option<Vec<&mut Expression>> {
+        match self {
+            Directive::END(d) => Some(vec![&mut d.first]),
+            Directive::BYTE(d) => Some(d.values.iter_mut().map(|(_, v)| v).collect()),
+            Directive::WORD(d) => Some(d.words.iter_mut().collect()),
+            Directive::RESB(d) => Some(vec![&mut d.bytes]),
+            Directive::RESW(d) => Some(vec![&mut d.words]),
+            Directive::ORG(d) => Some(d.address.iter_mut().map(|a| &mut **a).collect()),
+            Directive::BASE(d) => Some(vec![&mut d.address]),
+            Directive::EQU(d) => Some(vec![&mut d.value]),
+            _ => None,
+        }
+    }
+}
+
 impl Display for Directive {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -291,18 +402,36 @@ impl Display for Directive {
             ),
             Directive::END(e) => write!(f, "      \tEND\t{}", e.first),
             Directive::BYTE(b) => {
-                let data = b
-                    .data
+                // runs of constant bytes in hex, with the expressions in between
+                let mut elements = vec![];
+                let mut start = 0;
+                for (offset, value) in &b.values {
+                    if *offset > start {
+                        elements.push(hex(&b.data[start..*offset]));
+                    }
+                    elements.push(value.to_string());
+                    start = offset + 1;
+                }
+                if start < b.data.len() || b.values.is_empty() {
+                    elements.push(hex(&b.data[start..]));
+                }
+                write!(f, "      \tBYTE\t{}", elements.join(","))
+            }
+            Directive::WORD(w) => {
+                let words = w
+                    .words
                     .iter()
-                    .map(|b| format!("{:02X}", b))
+                    .map(|w| w.to_string())
                     .collect::<Vec<String>>()
-                    .join("");
-                write!(f, "      \tBYTE\t{data}")
+                    .join(",");
+                write!(f, "      \tWORD\t{words}")
             }
-            Directive::WORD(w) => write!(f, "      \tWORD\t{}", w.word),
             Directive::RESB(r) => write!(f, "      \tRESB\t{}", r.bytes),
             Directive::RESW(r) => write!(f, "      \tRESW\t{}", r.words),
-            Directive::ORG(o) => write!(f, "      \tORG\t{}", o.address),
+            Directive::ORG(o) => match o.address {
+                Some(ref address) => write!(f, "      \tORG\t{}", address),
+                None => write!(f, "      \tORG"),
+            },
             Directive::BASE(b) => write!(f, "      \tBASE\t{}", b.address),
             Directive::NOBASE(_) => write!(f, "      \tNOBASE"),
             Directive::EQU(e) => write!(f, "      \tEQU\t{}", e.value),
@@ -321,29 +450,252 @@ impl Display for Directive {
     }
 }
 
-pub fn literal_to_data(operand: &str) -> Result<Vec<u8>, String> {
-    if operand.starts_with("C'") && operand.ends_with('\'') {
-        let data = operand[2..operand.len() - 1].as_bytes().to_vec();
-        Ok(data)
-    } else if operand.starts_with("X'") && operand.ends_with('\'') {
-        let operand = if operand.len() % 2 == 0 {
-            let mut operand = operand[2..operand.len() - 1].to_string();
-            operand.insert(0, '0');
-            operand
-        } else {
-            operand[2..operand.len() - 1].to_string()
-        };
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
 
-        let data = operand
-            .as_bytes()
-            .chunks(2)
-            .map(|chunk| {
-                let hex_str = std::str::from_utf8(chunk).unwrap();
-                u8::from_str_radix(hex_str, 16).unwrap()
-            })
-            .collect::<Vec<u8>>();
-        Ok(data)
+/// Splits a comma-separated operand list, commas inside quotes are kept.
+pub fn split_list(operand: &str) -> Vec<&str> {
+    let mut elements = vec![];
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in operand.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '\'' {
+            quoted = !quoted;
+        } else if c == ',' && !quoted {
+            elements.push(&operand[start..i]);
+            start = i + 1;
+        }
+    }
+    elements.push(&operand[start..]);
+
+    elements
+}
+
+/// Converts a `C'...'`, `X'...'` or `F'...'` constant into its data,
+/// character constants are encoded in the given character set.
+pub fn literal_to_data(operand: &str, charset: Charset) -> Result<Vec<u8>, String> {
+    let quoted = |prefix: &str| {
+        operand
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix('\''))
+    };
+
+    if let Some(chars) = quoted("C'") {
+        chars_to_data(chars, charset).map_err(|e| format!("{e} in {operand}"))
+    } else if let Some(digits) = quoted("X'") {
+        hex_to_data(digits).map_err(|e| format!("{e} in {operand}"))
+    } else if let Some(value) = quoted("F'") {
+        let value = value
+            .parse::<f64>()
+            .map_err(|_| "Failed to parse float".to_string())?;
+        float_to_data(value)
     } else {
-        Err("Invalid literal".to_string())
+        Err(format!("Invalid literal {operand}"))
+    }
+}
+
+/// Decodes the characters of a `C'...'` constant.
+/// A quote is written as `''` or `\'`, and `\n`, `\t`, `\\` and `\xNN` are the other escapes.
+/// `\xNN` is taken as is, every other character is encoded in the character set.
+fn chars_to_data(chars: &str, charset: Charset) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(chars.len());
+    let mut iter = chars.chars();
+
+    while let Some(c) = iter.next() {
+        let byte = match c {
+            '\'' => match iter.next() {
+                Some('\'') => b'\'',
+                _ => return Err("Unpaired quote".to_string()),
+            },
+            '\\' => match iter.next() {
+                Some('n') => b'\n',
+                Some('t') => b'\t',
+                Some('\\') => b'\\',
+                Some('\'') => b'\'',
+                Some('x') => {
+                    let digits = iter.by_ref().take(2).collect::<String>();
+                    if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return Err(format!("Invalid escape sequence \\x{digits}"));
+                    }
+                    data.push(u8::from_str_radix(&digits, 16).unwrap());
+                    continue;
+                }
+                Some(c) => return Err(format!("Invalid escape sequence \\{c}")),
+                None => return Err("Unfinished escape sequence".to_string()),
+            },
+            c if c.is_ascii() => c as u8,
+            c => return Err(format!("Non-ASCII character '{c}'")),
+        };
+        data.push(charset.encode(byte));
+    }
+
+    Ok(data)
+}
+
+/// Decodes the digits of an `X'...'` constant, two digits per byte.
+fn hex_to_data(digits: &str) -> Result<Vec<u8>, String> {
+    if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hexadecimal digit '{c}'"));
+    }
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err("Odd number of hexadecimal digits".to_string());
+    }
+
+    Ok(digits
+        .as_bytes()
+        .chunks(2)
+        .map(|chunk| u8::from_str_radix(std::str::from_utf8(chunk).unwrap(), 16).unwrap())
+        .collect())
+}
+
+/// Encodes a number in the 48-bit SIC/XE floating-point format:
+/// 1 sign bit, 11-bit exponent (excess 1024) and a normalized 36-bit fraction,
+/// where the value is `fraction * 2^(exponent - 1024)` and `0.5 <= fraction < 1`.
+pub fn float_to_data(value: f64) -> Result<Vec<u8>, String> {
+    if !value.is_finite() {
+        return Err("Float constant is not finite".to_string());
+    }
+
+    if value == 0.0 {
+        return Ok(vec![0; 6]);
+    }
+
+    let mut fraction = value.abs();
+    let mut exponent: i64 = 1024;
+    while fraction >= 1.0 {
+        fraction /= 2.0;
+        exponent += 1;
+    }
+    while fraction < 0.5 {
+        fraction *= 2.0;
+        exponent -= 1;
+    }
+
+    let mut fraction = (fraction * (1u64 << 36) as f64).round() as u64;
+    if fraction == 1 << 36 {
+        // rounding carried into the next binary place
+        fraction >>= 1;
+        exponent += 1;
+    }
+
+    if !(0..=2047).contains(&exponent) {
+        return Err("Float constant out of range".to_string());
+    }
+
+    let sign = (value < 0.0) as u64;
+    let bits = sign << 47 | (exponent as u64) << 36 | fraction;
+    Ok((0..6).rev().map(|i| (bits >> (i * 8)) as u8).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn float_one() {
+        let data = literal_to_data("F'1'", Charset::Ascii).unwrap();
+        assert_eq!(data, vec![0x40, 0x18, 0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn float_negative_fraction() {
+        let data = literal_to_data("F'-0.75'", Charset::Ascii).unwrap();
+        assert_eq!(data, vec![0xC0, 0x0C, 0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn float_zero() {
+        let data = literal_to_data("F'0.0'", Charset::Ascii).unwrap();
+        assert_eq!(data, vec![0; 6]);
+    }
+
+    #[test]
+    fn float_rounding() {
+        // 0.1 = 0.8 * 2^-3, fraction rounds up in the last place
+        let data = float_to_data(0.1).unwrap();
+        assert_eq!(data, vec![0x3F, 0xDC, 0xCC, 0xCC, 0xCC, 0xCD]);
+    }
+
+    #[test]
+    fn char_escapes() {
+        let data = literal_to_data(r"C'IT''S\n\t\x00\\\''", Charset::Ascii).unwrap();
+        assert_eq!(data, b"IT'S\n\t\0\\'".to_vec());
+    }
+
+    #[test]
+    fn char_invalid() {
+        assert_eq!(
+            literal_to_data(r"C'A\qB'", Charset::Ascii).unwrap_err(),
+            r"Invalid escape sequence \q in C'A\qB'"
+        );
+        assert!(literal_to_data(r"C'\x4'", Charset::Ascii).is_err());
+        assert!(literal_to_data("C'IT'S'", Charset::Ascii).is_err());
+    }
+
+    #[test]
+    fn hex_invalid() {
+        assert_eq!(
+            literal_to_data("X'F1'", Charset::Ascii).unwrap(),
+            vec![0xF1]
+        );
+        assert_eq!(
+            literal_to_data("X'ZZ'", Charset::Ascii).unwrap_err(),
+            "Invalid hexadecimal digit 'Z' in X'ZZ'"
+        );
+        assert_eq!(
+            literal_to_data("X'F10'", Charset::Ascii).unwrap_err(),
+            "Odd number of hexadecimal digits in X'F10'"
+        );
+        assert!(literal_to_data("X''", Charset::Ascii).is_err());
+    }
+
+    #[test]
+    fn data_lists() {
+        let byte = Directive::parse("BYTE", Some("X'01',C'A,B',255,-1"), None);
+        let Some(Ok(Directive::BYTE(byte))) = byte else {
+            panic!("Expected BYTE directive");
+        };
+        assert_eq!(byte.data, vec![0x01, b'A', b',', b'B', 0xFF, 0xFF]);
+
+        let byte = Directive::parse("BYTE", Some("1,LEN-1,X'0203'"), None);
+        let Some(Ok(byte)) = byte else {
+            panic!("Expected BYTE directive");
+        };
+        assert_eq!(byte.size(), Some(4));
+        assert_eq!(byte.to_string(), "      \tBYTE\t01,LEN-1,0203");
+
+        let word = Directive::parse("WORD", Some("1,2,LABEL+3"), None);
+        let Some(Ok(word)) = word else {
+            panic!("Expected WORD directive");
+        };
+        assert_eq!(word.size(), Some(9));
+        assert_eq!(word.to_string(), "      \tWORD\t1,2,LABEL+3");
+
+        let err = Directive::parse("BYTE", Some("1,,2"), None).unwrap();
+        assert_eq!(err.unwrap_err(), "Missing element in list");
+        let err = Directive::parse("BYTE", Some("256"), None).unwrap();
+        assert_eq!(err.unwrap_err(), "Byte value 256 out of range");
+    }
+
+    #[test]
+    fn float_invalid() {
+        assert!(literal_to_data("F'ABC'", Charset::Ascii).is_err());
+        assert!(float_to_data(f64::INFINITY).is_err());
+        assert!(float_to_data(1e308).is_err());
+    }
+
+    #[test]
+    fn float_byte_size() {
+        let d = Directive::parse("BYTE", Some("F'3.14'"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(d.size(), Some(6));
     }
 }
@@ -1,9 +1,16 @@
 use std::fmt::Display;
 
+use super::charset::Charset;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ExpressionOperand {
     Symbol(String),
+    /// An absolute value, e.g. a constant.
     Value(i32),
+    /// A resolved address relative to the start of the program, e.g. a label.
+    Address(i32),
+    /// A reference to a symbol defined in another control section.
+    External(String),
     Locctr,
 }
 
@@ -13,19 +20,72 @@ impl TryFrom<&str> for ExpressionOperand {
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         if value == "*" {
             Ok(ExpressionOperand::Locctr)
-        } else if value.chars().all(|c| c.is_ascii_digit()) {
-            // All characters are digits
-            let value = value
-                .parse::<i32>()
-                .map_err(|_| "Failed to parse value".to_string())?;
-            Ok(ExpressionOperand::Value(value))
+        } else if value.starts_with(|c: char| c.is_ascii_digit()) || is_quoted_constant(value) {
+            // Symbols never start with a digit
+            Ok(ExpressionOperand::Value(parse_constant(value)?))
         } else {
-            // Not all characters are digits
             Ok(ExpressionOperand::Symbol(value.to_string()))
         }
     }
 }
 
+fn is_quoted_constant(value: &str) -> bool {
+    let mut chars = value.chars();
+    matches!(chars.next(), Some('X' | 'B' | 'C')) && chars.next() == Some('\'')
+}
+
+/// Parses a numeric constant, which can be:
+/// - decimal: `31`
+/// - hexadecimal: `X'1F'`, `0x1F` or `1FH`
+/// - binary: `B'11111'` or `0b11111`
+/// - character: `C'A'`, up to 3 characters packed into a word
+pub fn parse_constant(value: &str) -> Result<i32, String> {
+    parse_constant_with(value, Charset::default())
+}
+
+/// Parses a numeric constant like [`parse_constant`],
+/// with character constants encoded in the given character set.
+pub fn parse_constant_with(value: &str, charset: Charset) -> Result<i32, String> {
+    let (digits, radix) = if let Some(chars) = value.strip_prefix("C'") {
+        let chars = chars
+            .strip_suffix('\'')
+            .ok_or(format!("Invalid character constant \"{}\"", value))?;
+        if chars.is_empty() || chars.len() > 3 || !chars.is_ascii() {
+            return Err(format!(
+                "Character constant \"{}\" must be 1 to 3 ASCII characters",
+                value
+            ));
+        }
+        return Ok(chars
+            .bytes()
+            .fold(0, |acc, c| acc << 8 | charset.encode(c) as i32));
+    } else if let Some(digits) = value.strip_prefix("X'") {
+        (digits.strip_suffix('\'').unwrap_or("'"), 16)
+    } else if let Some(digits) = value.strip_prefix("B'") {
+        (digits.strip_suffix('\'').unwrap_or("'"), 2)
+    } else if let Some(digits) = value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        (digits, 16)
+    } else if let Some(digits) = value.strip_prefix("0b").or(value.strip_prefix("0B")) {
+        (digits, 2)
+    } else if let Some(digits) = value.strip_suffix('H').or(value.strip_suffix('h')) {
+        (digits, 16)
+    } else {
+        (value, 10)
+    };
+
+    let name = match radix {
+        16 => "hexadecimal",
+        2 => "binary",
+        _ => "decimal",
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(format!("Invalid {} constant \"{}\"", name, value));
+    }
+
+    i32::from_str_radix(digits, radix)
+        .map_err(|_| format!("The {} constant \"{}\" is too large", name, value))
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ExpressionOperator {
     Add,
@@ -45,127 +105,440 @@ impl Display for ExpressionOperator {
     }
 }
 
+impl ExpressionOperator {
+    fn precedence(&self) -> u8 {
+        match self {
+            ExpressionOperator::Add | ExpressionOperator::Subtract => 1,
+            ExpressionOperator::Multiply | ExpressionOperator::Divide => 2,
+        }
+    }
+
+    fn apply(&self, left: i32, right: i32) -> Option<i32> {
+        match self {
+            ExpressionOperator::Add => left.checked_add(right),
+            ExpressionOperator::Subtract => left.checked_sub(right),
+            ExpressionOperator::Multiply => left.checked_mul(right),
+            ExpressionOperator::Divide => left.checked_div(right),
+        }
+    }
+}
+
+/// The type of a resolved expression, following the SIC/XE rules:
+/// relative terms must be paired with opposite signs, leaving at most one positive
+/// relative term, and cannot be used in multiplication or division.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpressionType {
+    /// A value independent of the program location, e.g. `5` or `BUFEND-BUFFER`.
+    Absolute,
+    /// An address relative to the start of the program, e.g. `BUFFER+3`.
+    Relative,
+    /// A value depending on symbols of other control sections, filled in by the loader.
+    External(Vec<ExternalReference>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternalReference {
+    pub symbol: String,
+    pub negative: bool,
+}
+
+impl Display for ExternalReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.negative { '-' } else { '+' };
+        write!(f, "{}{}", sign, self.symbol)
+    }
+}
+
+/// A partially evaluated expression,
+/// counting the relative terms and collecting the external references.
+struct Term {
+    value: i32,
+    relative: i32,
+    externals: Vec<ExternalReference>,
+}
+
+impl Term {
+    fn is_absolute(&self) -> bool {
+        self.relative == 0 && self.externals.is_empty()
+    }
+
+    fn negate(self) -> Result<Term, String> {
+        Ok(Term {
+            value: self.value.checked_neg().ok_or("arithmetic overflow")?,
+            relative: -self.relative,
+            externals: self
+                .externals
+                .into_iter()
+                .map(|e| ExternalReference {
+                    symbol: e.symbol,
+                    negative: !e.negative,
+                })
+                .collect(),
+        })
+    }
+
+    fn combine(self, op: &ExpressionOperator, other: Term) -> Result<Term, String> {
+        match op {
+            ExpressionOperator::Add | ExpressionOperator::Subtract => {
+                let other = if *op == ExpressionOperator::Subtract {
+                    other.negate()?
+                } else {
+                    other
+                };
+
+                let mut externals = self.externals;
+                for external in other.externals {
+                    // a pair of the same external reference with opposite signs cancels out
+                    let pair = externals.iter().position(|e| {
+                        e.symbol == external.symbol && e.negative != external.negative
+                    });
+                    match pair {
+                        Some(index) => {
+                            externals.remove(index);
+                        }
+                        None => externals.push(external),
+                    }
+                }
+
+                Ok(Term {
+                    value: self
+                        .value
+                        .checked_add(other.value)
+                        .ok_or("arithmetic overflow")?,
+                    relative: self.relative + other.relative,
+                    externals,
+                })
+            }
+            ExpressionOperator::Multiply | ExpressionOperator::Divide => {
+                if !self.is_absolute() || !other.is_absolute() {
+                    return Err("relative terms cannot be multiplied or divided".to_string());
+                }
+                if *op == ExpressionOperator::Divide && other.value == 0 {
+                    return Err("division by zero".to_string());
+                }
+
+                Ok(Term {
+                    value: op
+                        .apply(self.value, other.value)
+                        .ok_or("arithmetic overflow")?,
+                    relative: 0,
+                    externals: vec![],
+                })
+            }
+        }
+    }
+}
+
 /// An expression is a combination of operands and operators, or a single operand.
-/// Notice: locctr `*` cannot be used in expressions, should be resolved before parsing.
+/// The locctr `*` must be resolved with [`Expression::resolve_locctr`] before evaluation.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
     Unsolved(UnsolvedExpression),
-    Resolved(i32),
+    Resolved(i32, ExpressionType),
     Literal(String),
 }
 
+/// A node of an expression tree.
 #[derive(Debug, Clone, PartialEq)]
-pub struct UnsolvedExpression {
-    pub left: ExpressionOperand,
-    pub op: Option<ExpressionOperator>,
-    pub right: Option<ExpressionOperand>,
+pub enum UnsolvedExpression {
+    Operand(ExpressionOperand),
+    Negate(Box<UnsolvedExpression>),
+    Binary(
+        Box<UnsolvedExpression>,
+        ExpressionOperator,
+        Box<UnsolvedExpression>,
+    ),
 }
 
-impl Expression {
-    pub fn eval(&self) -> Option<i32> {
-        match self {
-            Expression::Unsolved(expr) => match expr.op {
-                None => match expr.left {
-                    ExpressionOperand::Value(value) => Some(value),
-                    _ => None,
-                },
-                _ => {
-                    let left = match expr.left {
-                        ExpressionOperand::Value(value) => Some(value),
-                        _ => None,
-                    };
-                    let right = match expr.right {
-                        Some(ExpressionOperand::Value(value)) => Some(value),
-                        _ => None,
+impl UnsolvedExpression {
+    /// Builds the expression tree of a resolved value.
+    pub fn from_value(value: i32, kind: &ExpressionType) -> UnsolvedExpression {
+        match kind {
+            ExpressionType::Absolute => {
+                UnsolvedExpression::Operand(ExpressionOperand::Value(value))
+            }
+            ExpressionType::Relative => {
+                UnsolvedExpression::Operand(ExpressionOperand::Address(value))
+            }
+            ExpressionType::External(externals) => externals.iter().fold(
+                UnsolvedExpression::Operand(ExpressionOperand::Value(value)),
+                |expr, e| {
+                    let operand =
+                        UnsolvedExpression::Operand(ExpressionOperand::External(e.symbol.clone()));
+                    // omit the zero offset, e.g. `EXT` instead of `0+EXT`
+                    if expr == UnsolvedExpression::Operand(ExpressionOperand::Value(0)) {
+                        return if e.negative {
+                            UnsolvedExpression::Negate(Box::new(operand))
+                        } else {
+                            operand
+                        };
+                    }
+
+                    let op = if e.negative {
+                        ExpressionOperator::Subtract
+                    } else {
+                        ExpressionOperator::Add
                     };
+                    UnsolvedExpression::Binary(Box::new(expr), op, Box::new(operand))
+                },
+            ),
+        }
+    }
 
-                    match (left, right) {
-                        (Some(left), Some(right)) => match expr.op {
-                            Some(ExpressionOperator::Add) => Some(left + right),
-                            Some(ExpressionOperator::Subtract) => Some(left - right),
-                            Some(ExpressionOperator::Multiply) => Some(left * right),
-                            Some(ExpressionOperator::Divide) => Some(left / right),
-                            None => None,
-                        },
-                        _ => None,
-                    }
+    /// Evaluates the expression tree, returns None if a symbol or the locctr is unresolved.
+    fn term(&self) -> Result<Option<Term>, String> {
+        let term = match self {
+            UnsolvedExpression::Operand(operand) => match operand {
+                ExpressionOperand::Value(value) => Term {
+                    value: *value,
+                    relative: 0,
+                    externals: vec![],
+                },
+                ExpressionOperand::Address(value) => Term {
+                    value: *value,
+                    relative: 1,
+                    externals: vec![],
+                },
+                ExpressionOperand::External(symbol) => Term {
+                    value: 0,
+                    relative: 0,
+                    externals: vec![ExternalReference {
+                        symbol: symbol.clone(),
+                        negative: false,
+                    }],
+                },
+                ExpressionOperand::Symbol(_) | ExpressionOperand::Locctr => return Ok(None),
+            },
+            UnsolvedExpression::Negate(expr) => match expr.term()? {
+                Some(term) => term.negate()?,
+                None => return Ok(None),
+            },
+            UnsolvedExpression::Binary(left, op, right) => match (left.term()?, right.term()?) {
+                (Some(left), Some(right)) => left.combine(op, right)?,
+                _ => return Ok(None),
+            },
+        };
+
+        Ok(Some(term))
+    }
+
+    fn deps<'a>(&'a self, deps: &mut Vec<&'a str>) {
+        match self {
+            UnsolvedExpression::Operand(ExpressionOperand::Symbol(symbol)) => deps.push(symbol),
+            UnsolvedExpression::Operand(_) => {}
+            UnsolvedExpression::Negate(expr) => expr.deps(deps),
+            UnsolvedExpression::Binary(left, _, right) => {
+                left.deps(deps);
+                right.deps(deps);
+            }
+        }
+    }
+
+    fn substitute(
+        &mut self,
+        f: &mut impl FnMut(&ExpressionOperand) -> Option<UnsolvedExpression>,
+    ) -> bool {
+        match self {
+            UnsolvedExpression::Operand(operand) => match f(operand) {
+                Some(expr) => {
+                    *self = expr;
+                    true
                 }
+                None => false,
             },
-            Expression::Resolved(value) => Some(*value),
-            Expression::Literal(_) => {
-                panic!("Literal expression should be resolved before evaluation")
+            UnsolvedExpression::Negate(expr) => expr.substitute(f),
+            UnsolvedExpression::Binary(left, _, right) => {
+                let left = left.substitute(f);
+                let right = right.substitute(f);
+                left || right
             }
         }
     }
 
-    pub fn eval_and_update(&mut self) -> Option<i32> {
-        let res = self.eval();
-        if let Some(value) = res {
-            *self = Expression::Resolved(value);
+    fn precedence(&self) -> u8 {
+        match self {
+            UnsolvedExpression::Operand(_) => 4,
+            UnsolvedExpression::Negate(_) => 3,
+            UnsolvedExpression::Binary(_, op, _) => op.precedence(),
         }
+    }
+}
 
-        res
+impl Expression {
+    /// Returns the value of the expression if it can be evaluated.
+    pub fn eval(&self) -> Option<i32> {
+        match self {
+            Expression::Unsolved(expr) => expr.term().ok().flatten().map(|term| term.value),
+            Expression::Resolved(value, _) => Some(*value),
+            // literals are replaced with the labels of their pool entries before evaluation
+            Expression::Literal(_) => None,
+        }
     }
 
-    pub fn deps(&self) -> Vec<&str> {
+    /// Returns the type of a resolved expression.
+    pub fn kind(&self) -> Option<&ExpressionType> {
         match self {
-            Expression::Resolved(_) => vec![],
-            Expression::Unsolved(expr) => {
-                let mut deps = Vec::new();
+            Expression::Resolved(_, kind) => Some(kind),
+            _ => None,
+        }
+    }
 
-                if let ExpressionOperand::Symbol(ref symbol) = expr.left {
-                    deps.push(symbol.as_str())
-                }
+    /// Evaluates the value and the type of the expression.
+    /// Returns None if the expression depends on unresolved symbols,
+    /// or an error if the expression breaks the rules of relative terms.
+    pub fn resolve(&self) -> Result<Option<(i32, ExpressionType)>, String> {
+        let expr = match self {
+            Expression::Unsolved(expr) => expr,
+            Expression::Resolved(value, kind) => return Ok(Some((*value, kind.clone()))),
+            Expression::Literal(_) => return Ok(None),
+        };
+
+        let term = match expr.term() {
+            Ok(Some(term)) => term,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(format!("Illegal expression {}: {}", self, e)),
+        };
+        let kind = match (term.relative, term.externals.is_empty()) {
+            (0, true) => ExpressionType::Absolute,
+            (1, true) => ExpressionType::Relative,
+            (0, false) => ExpressionType::External(term.externals),
+            (1, false) => {
+                return Err(format!(
+                    "Illegal expression {}: relative and external terms cannot be mixed",
+                    self
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "Illegal expression {}: relative terms must be paired",
+                    self
+                ))
+            }
+        };
 
-                if let Some(ExpressionOperand::Symbol(ref symbol)) = expr.right {
-                    deps.push(symbol.as_str())
+        Ok(Some((term.value, kind)))
+    }
+
+    pub fn eval_and_update(&mut self) -> Result<Option<i32>, String> {
+        let res = self.resolve()?;
+        if let Some((value, kind)) = res {
+            *self = Expression::Resolved(value, kind);
+            return Ok(Some(value));
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the symbols the expression depends on, in order of appearance.
+    pub fn deps(&self) -> Vec<&str> {
+        let mut deps = Vec::new();
+        if let Expression::Unsolved(expr) = self {
+            expr.deps(&mut deps);
+        }
+        deps
+    }
+
+    /// Replaces the symbols with the values returned by `lookup`.
+    /// Returns whether any symbol is replaced.
+    pub fn substitute(
+        &mut self,
+        mut lookup: impl FnMut(&str) -> Option<(i32, ExpressionType)>,
+    ) -> bool {
+        match self {
+            Expression::Unsolved(expr) => expr.substitute(&mut |operand| match operand {
+                ExpressionOperand::Symbol(symbol) => {
+                    lookup(symbol).map(|(value, kind)| UnsolvedExpression::from_value(value, &kind))
                 }
+                _ => None,
+            }),
+            _ => false,
+        }
+    }
 
-                deps
-            }
-            Expression::Literal(_) => vec![],
+    /// Renames the symbols for which `rename` returns a new name.
+    pub fn rename(&mut self, mut rename: impl FnMut(&str) -> Option<String>) {
+        if let Expression::Unsolved(expr) = self {
+            expr.substitute(&mut |operand| match operand {
+                ExpressionOperand::Symbol(symbol) => rename(symbol)
+                    .map(|name| UnsolvedExpression::Operand(ExpressionOperand::Symbol(name))),
+                _ => None,
+            });
+        }
+    }
+
+    /// Replaces the locctr `*` with the given address.
+    pub fn resolve_locctr(&mut self, locctr: i32) {
+        if let Expression::Unsolved(expr) = self {
+            expr.substitute(&mut |operand| match operand {
+                ExpressionOperand::Locctr => Some(UnsolvedExpression::Operand(
+                    ExpressionOperand::Address(locctr),
+                )),
+                _ => None,
+            });
         }
     }
 }
 
-impl Display for Expression {
+impl Display for ExpressionOperand {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Expression::Resolved(value) => write!(f, "{}", value),
-            Expression::Unsolved(expr) => {
-                let mut string = String::new();
-
-                match expr.left {
-                    ExpressionOperand::Symbol(ref symbol) => string.push_str(symbol),
-                    ExpressionOperand::Value(value) => string.push_str(value.to_string().as_str()),
-                    ExpressionOperand::Locctr => string.push('*'),
-                }
+            ExpressionOperand::Symbol(symbol) => write!(f, "{}", symbol),
+            ExpressionOperand::Value(value) => write!(f, "{}", value),
+            ExpressionOperand::Address(value) => write!(f, "{}", value),
+            ExpressionOperand::External(symbol) => write!(f, "{}", symbol),
+            ExpressionOperand::Locctr => write!(f, "*"),
+        }
+    }
+}
 
-                match expr.op {
-                    Some(ExpressionOperator::Add) => string.push('+'),
-                    Some(ExpressionOperator::Subtract) => string.push('-'),
-                    Some(ExpressionOperator::Multiply) => string.push('*'),
-                    Some(ExpressionOperator::Divide) => string.push('/'),
-                    None => {}
+impl Display for UnsolvedExpression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnsolvedExpression::Operand(operand) => write!(f, "{}", operand),
+            UnsolvedExpression::Negate(expr) => {
+                if expr.precedence() < self.precedence() {
+                    write!(f, "-({})", expr)
+                } else {
+                    write!(f, "-{}", expr)
                 }
-
-                match expr.right {
-                    Some(ExpressionOperand::Symbol(ref symbol)) => string.push_str(symbol),
-                    Some(ExpressionOperand::Value(value)) => {
-                        string.push_str(value.to_string().as_str())
-                    }
-                    Some(ExpressionOperand::Locctr) => string.push('*'),
-                    None => {}
+            }
+            UnsolvedExpression::Binary(left, op, right) => {
+                if left.precedence() < op.precedence() {
+                    write!(f, "({})", left)?;
+                } else {
+                    write!(f, "{}", left)?;
                 }
 
-                write!(f, "{}", string)
+                write!(f, "{}", op)?;
+
+                // a right operand of the same precedence needs parentheses, e.g. A-(B-C)
+                if right.precedence() <= op.precedence() {
+                    write!(f, "({})", right)
+                } else {
+                    write!(f, "{}", right)
+                }
             }
+        }
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::Resolved(value, _) => write!(f, "{}", value),
+            Expression::Unsolved(expr) => write!(f, "{}", expr),
             Expression::Literal(ref literal) => write!(f, "{}", literal),
         }
     }
 }
 
 pub fn parse(input: &str) -> Result<Expression, String> {
+    parse_with(input, Charset::default())
+}
+
+/// Parses an expression like [`parse`], with character constants such as `C'A'`
+/// encoded in the given character set.
+pub fn parse_with(input: &str, charset: Charset) -> Result<Expression, String> {
     if input.is_empty() {
         return Err("Input is empty".to_string());
     }
@@ -178,70 +551,182 @@ pub fn parse(input: &str) -> Result<Expression, String> {
         let value = input
             .parse::<i32>()
             .map_err(|_| "Failed to parse value".to_string())?;
-        return Ok(Expression::Resolved(value));
+        return Ok(Expression::Resolved(value, ExpressionType::Absolute));
+    }
+
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        charset,
+    };
+    let expr = parser.parse_expression()?;
+    if let Some(c) = parser.peek() {
+        return Err(format!("Unexpected character '{}' in expression", c));
     }
 
-    let mut operator = None;
-    let mut split_index = None;
-    let mut operator_count = 0;
+    Ok(Expression::Unsolved(expr))
+}
+
+/// A recursive descent parser of the grammar:
+///
+/// ```text
+/// expression := term (('+' | '-') term)*
+/// term       := unary (('*' | '/') unary)*
+/// unary      := ('+' | '-') unary | primary
+/// primary    := '(' expression ')' | '*' | value | symbol
+/// ```
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    charset: Charset,
+}
 
-    for (index, char) in input.chars().enumerate() {
-        if index == 0 && char == '*' {
-            // Skip the locctr
-            continue;
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn parse_expression(&mut self) -> Result<UnsolvedExpression, String> {
+        let mut left = self.parse_term()?;
+        while let Some(c) = self.peek() {
+            let op = match c {
+                '+' => ExpressionOperator::Add,
+                '-' => ExpressionOperator::Subtract,
+                _ => break,
+            };
+            self.pos += 1;
+            let right = self.parse_term()?;
+            left = UnsolvedExpression::Binary(Box::new(left), op, Box::new(right));
         }
+        Ok(left)
+    }
 
-        match char {
-            '+' | '-' | '*' | '/' => {
-                operator_count += 1;
-                if operator_count > 1 {
-                    // If more than one operator is found, return an error
-                    return Err("Multiple operators detected".to_string());
-                }
+    fn parse_term(&mut self) -> Result<UnsolvedExpression, String> {
+        let mut left = self.parse_unary()?;
+        while let Some(c) = self.peek() {
+            let op = match c {
+                '*' => ExpressionOperator::Multiply,
+                '/' => ExpressionOperator::Divide,
+                _ => break,
+            };
+            self.pos += 1;
+            let right = self.parse_unary()?;
+            left = UnsolvedExpression::Binary(Box::new(left), op, Box::new(right));
+        }
+        Ok(left)
+    }
 
-                operator = match char {
-                    '+' => Some(ExpressionOperator::Add),
-                    '-' => Some(ExpressionOperator::Subtract),
-                    '*' => Some(ExpressionOperator::Multiply),
-                    '/' => Some(ExpressionOperator::Divide),
-                    _ => None, // This case will never occur due to the match pattern
-                };
-                split_index = Some(index);
+    fn parse_unary(&mut self) -> Result<UnsolvedExpression, String> {
+        match self.peek() {
+            Some('+') => {
+                self.pos += 1;
+                self.parse_unary()
             }
-            _ => {}
+            Some('-') => {
+                self.pos += 1;
+                let expr = self.parse_unary()?;
+                Ok(UnsolvedExpression::Negate(Box::new(expr)))
+            }
+            _ => self.parse_primary(),
         }
     }
 
-    if operator_count == 0 {
-        let operand = ExpressionOperand::try_from(input)?;
-        Ok(Expression::Unsolved(UnsolvedExpression {
-            left: operand,
-            op: None,
-            right: None,
-        }))
-    } else {
-        let (left, right) = input.split_at(split_index.unwrap());
-        let left_operand = ExpressionOperand::try_from(left)?;
-        let right_operand = ExpressionOperand::try_from(right[1..].to_string().as_str())?;
-
-        let expr = Expression::Unsolved(UnsolvedExpression {
-            left: left_operand,
-            op: operator,
-            right: Some(right_operand),
-        });
+    fn parse_primary(&mut self) -> Result<UnsolvedExpression, String> {
+        match self.peek() {
+            None => Err("Unexpected end of expression".to_string()),
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.parse_expression()?;
+                if self.peek() != Some(')') {
+                    return Err("Missing closing parenthesis".to_string());
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some('*') => {
+                self.pos += 1;
+                Ok(UnsolvedExpression::Operand(ExpressionOperand::Locctr))
+            }
+            Some('X' | 'B' | 'C') if self.chars.get(self.pos + 1) == Some(&'\'') => {
+                let start = self.pos;
+                self.pos += 2;
+                while self.peek().is_some_and(|c| c != '\'') {
+                    self.pos += 1;
+                }
+                if self.peek().is_none() {
+                    return Err("Constant not closed".to_string());
+                }
+                self.pos += 1;
 
-        Ok(expr)
+                let constant = self.chars[start..self.pos].iter().collect::<String>();
+                Ok(UnsolvedExpression::Operand(ExpressionOperand::Value(
+                    parse_constant_with(&constant, self.charset)?,
+                )))
+            }
+            Some('?') => {
+                // local labels, scoped to the routine they are used in
+                let start = self.pos;
+                self.pos += 1;
+                while self.peek().is_some_and(is_operand_char) {
+                    self.pos += 1;
+                }
+                let label = self.chars[start..self.pos].iter().collect::<String>();
+                if label.len() == 1 {
+                    return Err("Missing local label name after '?'".to_string());
+                }
+                Ok(UnsolvedExpression::Operand(ExpressionOperand::Symbol(
+                    label,
+                )))
+            }
+            Some(c) if is_operand_char(c) => {
+                let start = self.pos;
+                while self.peek().is_some_and(is_operand_char) {
+                    self.pos += 1;
+                }
+                let operand = self.chars[start..self.pos].iter().collect::<String>();
+                Ok(UnsolvedExpression::Operand(ExpressionOperand::try_from(
+                    operand.as_str(),
+                )?))
+            }
+            Some(c) => Err(format!("Unexpected character '{}' in expression", c)),
+        }
     }
 }
 
+fn is_operand_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '$'
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn symbol(name: &str) -> Box<UnsolvedExpression> {
+        Box::new(UnsolvedExpression::Operand(ExpressionOperand::Symbol(
+            name.to_string(),
+        )))
+    }
+
+    fn value(value: i32) -> Box<UnsolvedExpression> {
+        Box::new(UnsolvedExpression::Operand(ExpressionOperand::Value(value)))
+    }
+
     #[test]
     fn test_multiple_operator() {
-        let expr = parse("1+2+3");
-        assert_eq!(expr, Err("Multiple operators detected".to_string()));
+        let expr = parse("1+2+3").unwrap();
+        assert_eq!(
+            expr,
+            Expression::Unsolved(UnsolvedExpression::Binary(
+                Box::new(UnsolvedExpression::Binary(
+                    value(1),
+                    ExpressionOperator::Add,
+                    value(2)
+                )),
+                ExpressionOperator::Add,
+                value(3),
+            ))
+        );
+        assert_eq!(expr.eval(), Some(6));
     }
 
     #[test]
@@ -249,11 +734,11 @@ mod tests {
         let expr = parse("1*2");
         assert_eq!(
             expr,
-            Ok(Expression::Unsolved(UnsolvedExpression {
-                left: ExpressionOperand::Value(1),
-                op: Some(ExpressionOperator::Multiply),
-                right: Some(ExpressionOperand::Value(2)),
-            }))
+            Ok(Expression::Unsolved(UnsolvedExpression::Binary(
+                value(1),
+                ExpressionOperator::Multiply,
+                value(2),
+            )))
         );
     }
 
@@ -262,11 +747,11 @@ mod tests {
         let expr = parse("ABC+123");
         assert_eq!(
             expr,
-            Ok(Expression::Unsolved(UnsolvedExpression {
-                left: ExpressionOperand::Symbol("ABC".to_string()),
-                op: Some(ExpressionOperator::Add),
-                right: Some(ExpressionOperand::Value(123)),
-            }))
+            Ok(Expression::Unsolved(UnsolvedExpression::Binary(
+                symbol("ABC"),
+                ExpressionOperator::Add,
+                value(123),
+            )))
         );
     }
 
@@ -275,11 +760,11 @@ mod tests {
         let expr = parse("123+ABC");
         assert_eq!(
             expr,
-            Ok(Expression::Unsolved(UnsolvedExpression {
-                left: ExpressionOperand::Value(123),
-                op: Some(ExpressionOperator::Add),
-                right: Some(ExpressionOperand::Symbol("ABC".to_string())),
-            }))
+            Ok(Expression::Unsolved(UnsolvedExpression::Binary(
+                value(123),
+                ExpressionOperator::Add,
+                symbol("ABC"),
+            )))
         );
     }
 
@@ -288,31 +773,51 @@ mod tests {
         let expr = parse("ABC-DEF");
         assert_eq!(
             expr,
-            Ok(Expression::Unsolved(UnsolvedExpression {
-                left: ExpressionOperand::Symbol("ABC".to_string()),
-                op: Some(ExpressionOperator::Subtract),
-                right: Some(ExpressionOperand::Symbol("DEF".to_string())),
-            }))
+            Ok(Expression::Unsolved(UnsolvedExpression::Binary(
+                symbol("ABC"),
+                ExpressionOperator::Subtract,
+                symbol("DEF"),
+            )))
         );
     }
 
     #[test]
     fn test_value() {
         let expr = parse("12345");
-        assert_eq!(expr, Ok(Expression::Resolved(12345)));
+        assert_eq!(
+            expr,
+            Ok(Expression::Resolved(12345, ExpressionType::Absolute))
+        );
     }
 
     #[test]
     fn test_symbol() {
         let expr = parse("ABC123");
+        assert_eq!(expr, Ok(Expression::Unsolved(*symbol("ABC123"))));
+    }
+
+    #[test]
+    fn test_local_label() {
+        let mut expr = parse("?LOOP-ABC").unwrap();
         assert_eq!(
             expr,
-            Ok(Expression::Unsolved(UnsolvedExpression {
-                left: ExpressionOperand::Symbol("ABC123".to_string()),
-                op: None,
-                right: None,
-            }))
+            Expression::Unsolved(UnsolvedExpression::Binary(
+                symbol("?LOOP"),
+                ExpressionOperator::Subtract,
+                symbol("ABC"),
+            ))
+        );
+        assert_eq!(
+            parse("A?B"),
+            Err("Unexpected character '?' in expression".to_string())
+        );
+        assert_eq!(
+            parse("?+1"),
+            Err("Missing local label name after '?'".to_string())
         );
+
+        expr.rename(|symbol| symbol.starts_with('?').then(|| format!("READ{}", symbol)));
+        assert_eq!(expr.deps(), vec!["READ?LOOP", "ABC"]);
     }
 
     #[test]
@@ -323,4 +828,245 @@ mod tests {
             Ok(Expression::Literal("C'IT\\'S A STRING'".to_string()))
         );
     }
+
+    #[test]
+    fn test_precedence() {
+        let expr = parse("BUFEND-BUFFER+3*2").unwrap();
+        assert_eq!(expr.to_string(), "BUFEND-BUFFER+3*2");
+        assert_eq!(expr.deps(), vec!["BUFEND", "BUFFER"]);
+
+        let mut expr = expr;
+        expr.substitute(|s| match s {
+            "BUFEND" => Some((100, ExpressionType::Relative)),
+            "BUFFER" => Some((40, ExpressionType::Relative)),
+            _ => None,
+        });
+        assert_eq!(expr.resolve(), Ok(Some((66, ExpressionType::Absolute))));
+    }
+
+    #[test]
+    fn test_parentheses() {
+        let expr = parse("(A+B)*2").unwrap();
+        assert_eq!(
+            expr,
+            Expression::Unsolved(UnsolvedExpression::Binary(
+                Box::new(UnsolvedExpression::Binary(
+                    symbol("A"),
+                    ExpressionOperator::Add,
+                    symbol("B")
+                )),
+                ExpressionOperator::Multiply,
+                value(2),
+            ))
+        );
+        assert_eq!(expr.to_string(), "(A+B)*2");
+        assert_eq!(parse("A-(B-C)").unwrap().to_string(), "A-(B-C)");
+        assert_eq!(parse("((A))").unwrap().to_string(), "A");
+    }
+
+    #[test]
+    fn test_unary() {
+        let expr = parse("-1").unwrap();
+        assert_eq!(
+            expr,
+            Expression::Unsolved(UnsolvedExpression::Negate(value(1)))
+        );
+        assert_eq!(expr.eval(), Some(-1));
+        assert_eq!(parse("+5").unwrap().eval(), Some(5));
+        assert_eq!(parse("2*-3").unwrap().eval(), Some(-6));
+        assert_eq!(parse("-(A+B)").unwrap().to_string(), "-(A+B)");
+    }
+
+    #[test]
+    fn test_locctr() {
+        let mut expr = parse("*-3").unwrap();
+        assert_eq!(
+            expr,
+            Expression::Unsolved(UnsolvedExpression::Binary(
+                Box::new(UnsolvedExpression::Operand(ExpressionOperand::Locctr)),
+                ExpressionOperator::Subtract,
+                value(3),
+            ))
+        );
+        assert_eq!(expr.eval(), None);
+        expr.resolve_locctr(0x1000);
+        assert_eq!(expr.eval(), Some(0x1000 - 3));
+
+        let mut expr = parse("**2").unwrap();
+        expr.resolve_locctr(4);
+        assert_eq!(
+            expr.resolve(),
+            Err(
+                "Illegal expression 4*2: relative terms cannot be multiplied or divided"
+                    .to_string()
+            )
+        );
+    }
+
+    fn resolve_with(input: &str) -> Result<Option<(i32, ExpressionType)>, String> {
+        let mut expr = parse(input).unwrap();
+        expr.substitute(|s| match s {
+            "FIRST" => Some((0x10, ExpressionType::Relative)),
+            "LAST" => Some((0x40, ExpressionType::Relative)),
+            "SIZE" => Some((0x30, ExpressionType::Absolute)),
+            "EXT" | "REF" => Some((
+                0,
+                ExpressionType::External(vec![ExternalReference {
+                    symbol: s.to_string(),
+                    negative: false,
+                }]),
+            )),
+            _ => None,
+        });
+        expr.resolve_locctr(0x20);
+        expr.resolve()
+    }
+
+    #[test]
+    fn test_types() {
+        use ExpressionType::*;
+
+        assert_eq!(resolve_with("SIZE*2"), Ok(Some((0x60, Absolute))));
+        assert_eq!(resolve_with("LAST-FIRST"), Ok(Some((0x30, Absolute))));
+        assert_eq!(resolve_with("FIRST+SIZE"), Ok(Some((0x40, Relative))));
+        assert_eq!(resolve_with("LAST-FIRST+*"), Ok(Some((0x50, Relative))));
+        assert_eq!(
+            resolve_with("-FIRST+LAST*1-0"),
+            Err(
+                "Illegal expression -16+64*1-0: relative terms cannot be multiplied or divided"
+                    .to_string()
+            )
+        );
+        assert_eq!(resolve_with("UNKNOWN+1"), Ok(None));
+        assert_eq!(
+            resolve_with("FIRST+LAST"),
+            Err("Illegal expression 16+64: relative terms must be paired".to_string())
+        );
+        assert_eq!(
+            resolve_with("SIZE-FIRST"),
+            Err("Illegal expression 48-16: relative terms must be paired".to_string())
+        );
+        assert_eq!(
+            resolve_with("FIRST*2"),
+            Err(
+                "Illegal expression 16*2: relative terms cannot be multiplied or divided"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            resolve_with("SIZE/(FIRST-FIRST)"),
+            Err("Illegal expression 48/(16-16): division by zero".to_string())
+        );
+    }
+
+    #[test]
+    fn test_external_types() {
+        let external = |refs: &[(&str, bool)]| {
+            ExpressionType::External(
+                refs.iter()
+                    .map(|(symbol, negative)| ExternalReference {
+                        symbol: symbol.to_string(),
+                        negative: *negative,
+                    })
+                    .collect(),
+            )
+        };
+
+        assert_eq!(
+            resolve_with("EXT-REF+4"),
+            Ok(Some((4, external(&[("EXT", false), ("REF", true)]))))
+        );
+        assert_eq!(
+            resolve_with("EXT-(REF-SIZE)"),
+            Ok(Some((0x30, external(&[("EXT", false), ("REF", true)]))))
+        );
+        assert_eq!(
+            resolve_with("EXT-EXT+SIZE"),
+            Ok(Some((0x30, ExpressionType::Absolute)))
+        );
+        assert_eq!(
+            resolve_with("EXT+FIRST"),
+            Err(
+                "Illegal expression EXT+16: relative and external terms cannot be mixed"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            resolve_with("EXT*2"),
+            Err(
+                "Illegal expression EXT*2: relative terms cannot be multiplied or divided"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            parse("(A+B"),
+            Err("Missing closing parenthesis".to_string())
+        );
+        assert_eq!(parse("A+"), Err("Unexpected end of expression".to_string()));
+        assert_eq!(
+            parse("A)"),
+            Err("Unexpected character ')' in expression".to_string())
+        );
+        assert_eq!(
+            parse("A%B"),
+            Err("Unexpected character '%' in expression".to_string())
+        );
+        assert_eq!(parse("1/0").unwrap().eval(), None);
+    }
+
+    #[test]
+    fn test_constants() {
+        for input in ["31", "X'1F'", "0x1F", "0X1f", "1FH", "B'11111'", "0b11111"] {
+            assert_eq!(parse_constant(input), Ok(31), "{input}");
+        }
+        assert_eq!(parse_constant("0FFH"), Ok(255));
+        assert_eq!(parse_constant("C'A'"), Ok(0x41));
+        assert_eq!(parse_constant("C'EOF'"), Ok(0x454F46));
+    }
+
+    #[test]
+    fn test_constants_in_expression() {
+        assert_eq!(parse("X'FF'").unwrap().eval(), Some(255));
+        assert_eq!(parse("0x0F0").unwrap().eval(), Some(0xF0));
+        assert_eq!(parse("C'Z'-C'A'+1").unwrap().eval(), Some(26));
+        assert_eq!(parse("LEN*B'10'").unwrap().deps(), vec!["LEN"]);
+        assert_eq!(parse("C'+'").unwrap().eval(), Some(0x2B));
+    }
+
+    #[test]
+    fn test_invalid_constants() {
+        assert_eq!(
+            parse("X'1G'"),
+            Err("Invalid hexadecimal constant \"X'1G'\"".to_string())
+        );
+        assert_eq!(
+            parse("0b102"),
+            Err("Invalid binary constant \"0b102\"".to_string())
+        );
+        assert_eq!(
+            parse("12AB"),
+            Err("Invalid decimal constant \"12AB\"".to_string())
+        );
+        assert_eq!(
+            parse("1+1GH"),
+            Err("Invalid hexadecimal constant \"1GH\"".to_string())
+        );
+        assert_eq!(
+            parse("X''"),
+            Err("Invalid hexadecimal constant \"X''\"".to_string())
+        );
+        assert_eq!(
+            parse("C'ABCD'"),
+            Err("Character constant \"C'ABCD'\" must be 1 to 3 ASCII characters".to_string())
+        );
+        assert_eq!(parse("X'FF"), Err("Constant not closed".to_string()));
+        assert_eq!(
+            parse("X'FFFFFFFFF'"),
+            Err("The hexadecimal constant \"X'FFFFFFFFF'\" is too large".to_string())
+        );
+    }
 }
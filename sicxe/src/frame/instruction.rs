@@ -1,6 +1,8 @@
 use std::fmt::Display;
 
+use super::charset::Charset;
 use super::expression::*;
+use super::optab::*;
 use super::FrameLike;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -18,6 +20,8 @@ pub struct Format1 {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Format2 {
     pub opcode: u8,
+    /// The operand signature of the operation, deciding how the fields are checked.
+    pub operands: OperandSignature,
     pub register1: Box<Expression>,
     pub register2: Box<Expression>,
 }
@@ -25,6 +29,8 @@ pub struct Format2 {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Format34 {
     pub opcode: u8,
+    /// The operand signature of the operation, e.g. `None` for RSUB.
+    pub operands: OperandSignature,
     pub nixbpe: u8,
     pub value: Box<Expression>,
 }
@@ -61,109 +67,122 @@ impl Format34 {
     }
 }
 
-impl FrameLike for Instruction {
-    fn size(&self) -> Option<i32> {
-        match self {
-            Instruction::Format1(_) => Some(1),
-            Instruction::Format2(_) => Some(2),
-            Instruction::Format34(f) => {
-                if f.nixbpe & 0b000001 == 0 {
-                    Some(3)
-                } else {
-                    Some(4)
-                }
-            }
-        }
-    }
-
-    fn parse(
+impl Instruction {
+    /// Parses an instruction, looking up the operator in the given operation code table.
+    /// If the operator is not in the table, return None.
+    pub fn parse_with(
+        optab: &OpTable,
+        charset: Charset,
         operator: &str,
         operand: Option<&str>,
-        _label: Option<&str>,
-    ) -> Option<Result<Self, String>> {
+    ) -> Option<Result<Instruction, String>> {
         let is_format4 = operator.starts_with('+');
         let operator = operator.trim_start_matches('+');
-        let format = match operator {
-            "ADDR" | "CLEAR" | "COMPR" | "DIVR" | "MULR" | "RMO" | "SHIFTL" | "SHIFTR" | "SUBR"
-            | "TIXR" => 2,
-            "ADD" | "AND" | "COMP" | "DIV" | "J" | "JEQ" | "JGT" | "JLT" | "JSUB" | "LDA"
-            | "LDCH" | "LDL" | "LDX" | "MUL" | "OR" | "RD" | "RSUB" | "STA" | "STCH" | "STL"
-            | "STX" | "SUB" | "TD" | "TIX" | "WD" => 3,
-            "LDB" | "LDS" | "LDT" | "STB" | "STS" | "STT" => 3,
-            _ => 0,
-        };
-
-        match format {
-            2 => {
-                if operand.is_none() {
-                    return Some(Err("Missing operand".to_string()));
-                }
+        let op = optab.get(operator)?;
 
-                let opcode = match operator {
-                    "ADDR" => Some(0x90),
-                    "CLEAR" => Some(0xB4),
-                    "COMPR" => Some(0xA0),
-                    "DIVR" => Some(0x9C),
-                    "MULR" => Some(0x98),
-                    "RMO" => Some(0xAC),
-                    "SHIFTL" => Some(0xA4),
-                    "SHIFTR" => Some(0xA8),
-                    "SUBR" => Some(0x94),
-                    "TIXR" => Some(0xB8),
-                    _ => None,
-                };
-                opcode?;
+        Some(Instruction::from_opcode(op, is_format4, operand, charset))
+    }
 
-                let mut operands = operand
-                    .unwrap()
-                    .split(',')
-                    .collect::<Vec<&str>>()
-                    .into_iter();
-                let register1: &str;
-                let mut register2 = "0";
-                match operator {
-                    "CLEAR" | "TIXR" => {
-                        if operands.len() != 1 {
-                            return Some(Err("Invalid number of operands".to_string()));
-                        }
+    /// Builds an instruction of the given operation from the operand field,
+    /// validating the operand against the operand signature of the operation.
+    /// Character constants in the operand are encoded in the given character set.
+    pub fn from_opcode(
+        op: &OpCode,
+        is_format4: bool,
+        operand: Option<&str>,
+        charset: Charset,
+    ) -> Result<Instruction, String> {
+        match op.format {
+            OpFormat::Format1 => {
+                if is_format4 {
+                    return Err("Format 1 instruction cannot be extended".to_string());
+                }
 
-                        register1 = operands.next().unwrap().trim();
-                    }
-                    _ => {
-                        if operands.len() != 2 {
-                            return Some(Err("Invalid number of operands".to_string()));
-                        }
+                if operand.is_some() {
+                    return Err("Unexpected operand".to_string());
+                }
 
-                        register1 = operands.next().unwrap().trim();
-                        register2 = operands.next().unwrap().trim();
+                Ok(Instruction::Format1(Format1 { opcode: op.opcode }))
+            }
+            OpFormat::Format2 => {
+                if is_format4 {
+                    return Err("Format 2 instruction cannot be extended".to_string());
+                }
+
+                let operands = operand
+                    .ok_or("Missing operand".to_string())?
+                    .split(',')
+                    .map(|s| s.trim())
+                    .collect::<Vec<&str>>();
+                let expected = match op.operands {
+                    OperandSignature::Register | OperandSignature::Number => 1,
+                    OperandSignature::RegisterRegister | OperandSignature::RegisterCount => 2,
+                    OperandSignature::None | OperandSignature::Memory => {
+                        return Err(format!("Invalid operand signature for {}", op.mnemonic));
                     }
+                };
+                if operands.len() != expected {
+                    return Err("Invalid number of operands".to_string());
+                }
+                if operands.iter().any(|operand| operand.starts_with('=')) {
+                    return Err("Literals cannot be used as Format 2 operands".to_string());
                 }
 
-                let register1 = parse(register1).unwrap();
-                let register2 = parse(register2).unwrap();
-                let register1 = Box::new(register1);
-                let register2 = Box::new(register2);
+                let absolute = |value: u8| {
+                    Box::new(Expression::Resolved(value as i32, ExpressionType::Absolute))
+                };
+                let (register1, register2) = match op.operands {
+                    OperandSignature::Register => {
+                        (absolute(parse_register(operands[0])?), absolute(0))
+                    }
+                    OperandSignature::Number => (
+                        parse_number(operands[0], SVC_NUMBERS, charset)?,
+                        absolute(0),
+                    ),
+                    // the shift count n is stored as n - 1
+                    OperandSignature::RegisterCount => {
+                        let count = match *parse_number(operands[1], SHIFT_COUNTS, charset)? {
+                            Expression::Resolved(n, kind) => Expression::Resolved(n - 1, kind),
+                            Expression::Unsolved(n) => {
+                                Expression::Unsolved(UnsolvedExpression::Binary(
+                                    Box::new(n),
+                                    ExpressionOperator::Subtract,
+                                    Box::new(UnsolvedExpression::Operand(
+                                        ExpressionOperand::Value(1),
+                                    )),
+                                ))
+                            }
+                            n => n,
+                        };
+                        (absolute(parse_register(operands[0])?), Box::new(count))
+                    }
+                    _ => (
+                        absolute(parse_register(operands[0])?),
+                        absolute(parse_register(operands[1])?),
+                    ),
+                };
 
-                Some(Ok(Instruction::Format2(Format2 {
-                    opcode: opcode.unwrap(),
+                Ok(Instruction::Format2(Format2 {
+                    opcode: op.opcode,
+                    operands: op.operands,
                     register1,
                     register2,
-                })))
+                }))
             }
-            3 => {
-                let has_operand = operand.is_some();
-                let mut operand = operand;
-                if operator == "RSUB" {
-                    if has_operand {
-                        return Some(Err("Invalid operand".to_string()));
+            OpFormat::Format34 => {
+                let operand = match op.operands {
+                    OperandSignature::None => {
+                        if operand.is_some() {
+                            return Err("Invalid operand".to_string());
+                        }
+                        "0"
                     }
+                    OperandSignature::Memory => operand.ok_or("Missing operand".to_string())?,
+                    _ => {
+                        return Err(format!("Invalid operand signature for {}", op.mnemonic));
+                    }
+                };
 
-                    operand = Some("0");
-                } else if !has_operand {
-                    return Some(Err("Missing operand".to_string()));
-                }
-
-                let operand = operand.unwrap();
                 let is_indirect = operand.starts_with('@');
                 let is_immediate = operand.starts_with('#');
                 let is_indexed = operand.ends_with(",X");
@@ -177,171 +196,41 @@ impl FrameLike for Instruction {
                     | if is_indirect { 0b100000 } else { 0b000000 }
                     | if is_indexed { 0b001000 } else { 0b000000 };
 
-                let value = parse(operand).ok()?;
-                let value = Box::new(value);
-                match operator {
-                    "ADD" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x18,
-                        nixbpe,
-                        value,
-                    }))),
-                    "AND" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x40,
-                        nixbpe,
-                        value,
-                    }))),
-                    "COMP" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x28,
-                        nixbpe,
-                        value,
-                    }))),
-                    "DIV" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x24,
-                        nixbpe,
-                        value,
-                    }))),
-                    "J" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x3C,
-                        nixbpe,
-                        value,
-                    }))),
-                    "JEQ" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x30,
-                        nixbpe,
-                        value,
-                    }))),
-                    "JGT" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x34,
-                        nixbpe,
-                        value,
-                    }))),
-                    "JLT" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x38,
-                        nixbpe,
-                        value,
-                    }))),
-                    "JSUB" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x48,
-                        nixbpe,
-                        value,
-                    }))),
-                    "LDA" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x00,
-                        nixbpe,
-                        value,
-                    }))),
-                    "LDCH" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x50,
-                        nixbpe,
-                        value,
-                    }))),
-                    "LDL" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x08,
-                        nixbpe,
-                        value,
-                    }))),
-                    "LDX" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x04,
-                        nixbpe,
-                        value,
-                    }))),
-                    "MUL" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x20,
-                        nixbpe,
-                        value,
-                    }))),
-                    "OR" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x44,
-                        nixbpe,
-                        value,
-                    }))),
-                    "RD" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0xD8,
-                        nixbpe,
-                        value,
-                    }))),
-                    "RSUB" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x4C,
-                        nixbpe,
-                        value,
-                    }))),
-                    "STA" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x0C,
-                        nixbpe,
-                        value,
-                    }))),
-                    "STCH" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x54,
-                        nixbpe,
-                        value,
-                    }))),
-                    "STL" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x14,
-                        nixbpe,
-                        value,
-                    }))),
-                    "STX" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x10,
-                        nixbpe,
-                        value,
-                    }))),
-                    "SUB" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x1C,
-                        nixbpe,
-                        value,
-                    }))),
-                    "TD" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0xE0,
-                        nixbpe,
-                        value,
-                    }))),
-                    "TIX" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x2C,
-                        nixbpe,
-                        value,
-                    }))),
-                    "WD" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0xDC,
-                        nixbpe,
-                        value,
-                    }))),
-                    "LDB" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x68,
-                        nixbpe,
-                        value,
-                    }))),
-                    "LDS" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x6C,
-                        nixbpe,
-                        value,
-                    }))),
-                    "LDT" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x74,
-                        nixbpe,
-                        value,
-                    }))),
-                    "STB" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x78,
-                        nixbpe,
-                        value,
-                    }))),
-                    "STS" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x7C,
-                        nixbpe,
-                        value,
-                    }))),
-                    "STT" => Some(Ok(Instruction::Format34(Format34 {
-                        opcode: 0x84,
-                        nixbpe,
-                        value,
-                    }))),
-                    _ => None,
+                let value = Box::new(parse_with(operand, charset)?);
+                Ok(Instruction::Format34(Format34 {
+                    opcode: op.opcode,
+                    operands: op.operands,
+                    nixbpe,
+                    value,
+                }))
+            }
+        }
+    }
+}
+
+impl FrameLike for Instruction {
+    fn size(&self) -> Option<i32> {
+        match self {
+            Instruction::Format1(_) => Some(1),
+            Instruction::Format2(_) => Some(2),
+            Instruction::Format34(f) => {
+                if f.nixbpe & 0b000001 == 0 {
+                    Some(3)
+                } else {
+                    Some(4)
                 }
             }
-            _ => None,
         }
     }
 
+    fn parse(
+        operator: &str,
+        operand: Option<&str>,
+        _label: Option<&str>,
+    ) -> Option<Result<Self, String>> {
+        Instruction::parse_with(OpTable::builtin(), Charset::default(), operator, operand)
+    }
+
     fn expressions(&self) -> Option<Vec<&Expression>> {
         match self {
             Instruction::Format1(_) => None,
@@ -349,6 +238,74 @@ impl FrameLike for Instruction {
             Instruction::Format34(f) => Some(vec![&f.value]),
         }
     }
+
+    fn expressions_mut(&mut self) -> Option<Vec<&mut Expression>> {
+        match self {
+            Instruction::Format1(_) => None,
+            Instruction::Format2(f) => Some(vec![&mut f.register1, &mut f.register2]),
+            Instruction::Format34(f) => Some(vec![&mut f.value]),
+        }
+    }
+}
+
+/// Returns the register number of a register mnemonic.
+pub fn register_number(name: &str) -> Option<u8> {
+    match name {
+        "A" => Some(0),
+        "X" => Some(1),
+        "L" => Some(2),
+        "B" => Some(3),
+        "S" => Some(4),
+        "T" => Some(5),
+        "F" => Some(6),
+        "PC" => Some(8),
+        "SW" => Some(9),
+        _ => None,
+    }
+}
+
+fn parse_register(operand: &str) -> Result<u8, String> {
+    register_number(operand).ok_or(format!("Invalid register \"{}\"", operand))
+}
+
+/// The service request numbers of SVC.
+pub const SVC_NUMBERS: std::ops::RangeInclusive<i32> = 0..=15;
+/// The shift counts of SHIFTL and SHIFTR.
+pub const SHIFT_COUNTS: std::ops::RangeInclusive<i32> = 1..=16;
+
+/// Parses the number field of SVC or the count of a shift, an absolute expression
+/// such as `4` or `N+1`. Its range is checked now if it is a constant,
+/// or by [`check_number`] once the symbols are resolved.
+fn parse_number(
+    operand: &str,
+    range: std::ops::RangeInclusive<i32>,
+    charset: Charset,
+) -> Result<Box<Expression>, String> {
+    let expected = || format!("Expected a number, found \"{}\"", operand);
+    let value = parse_with(operand, charset).map_err(|_| expected())?;
+    if matches!(value, Expression::Literal(_))
+        || value.deps().iter().any(|d| register_number(d).is_some())
+    {
+        return Err(expected());
+    }
+    if let Some(value) = value.eval() {
+        check_number(value, &range)?;
+    }
+
+    Ok(Box::new(value))
+}
+
+/// Checks that the number field of SVC or the count of a shift is in range.
+pub fn check_number(value: i32, range: &std::ops::RangeInclusive<i32>) -> Result<(), String> {
+    if !range.contains(&value) {
+        return Err(format!(
+            "Number {} out of range {}..{}",
+            value,
+            range.start(),
+            range.end()
+        ));
+    }
+    Ok(())
 }
 
 impl Display for Instruction {
@@ -356,9 +313,11 @@ impl Display for Instruction {
         match self {
             Instruction::Format1(ref i) => write!(f, "op: 0x{:02X}", i.opcode),
             Instruction::Format2(ref i) => {
-                let r1 = i.register1.to_string().parse::<u8>().unwrap();
-                let r2 = i.register2.to_string().parse::<u8>().unwrap();
-                write!(f, "op: 0x{:02X}, r1: {:01X}, r2: {:01X}", i.opcode, r1, r2)
+                // the number fields may still be expressions before symbols are resolved
+                let show =
+                    |r: &Expression| r.eval().map_or(r.to_string(), |r| format!("{:01X}", r));
+                let (r1, r2) = (show(&i.register1), show(&i.register2));
+                write!(f, "op: 0x{:02X}, r1: {}, r2: {}", i.opcode, r1, r2)
             }
             Instruction::Format34(ref i) => {
                 write!(
@@ -370,3 +329,163 @@ impl Display for Instruction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_format1(operator: &str) -> Format1 {
+        match Instruction::parse(operator, None, None) {
+            Some(Ok(Instruction::Format1(f))) => f,
+            other => panic!("Expected format 1 instruction, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn format1_fix() {
+        assert_eq!(parse_format1("FIX").opcode, 0xC4);
+    }
+
+    #[test]
+    fn format1_float() {
+        assert_eq!(parse_format1("FLOAT").opcode, 0xC0);
+    }
+
+    #[test]
+    fn format1_hio() {
+        assert_eq!(parse_format1("HIO").opcode, 0xF4);
+    }
+
+    #[test]
+    fn format1_norm() {
+        assert_eq!(parse_format1("NORM").opcode, 0xC8);
+    }
+
+    #[test]
+    fn format1_sio() {
+        assert_eq!(parse_format1("SIO").opcode, 0xF0);
+    }
+
+    #[test]
+    fn format1_tio() {
+        assert_eq!(parse_format1("TIO").opcode, 0xF8);
+    }
+
+    #[test]
+    fn format1_size_and_display() {
+        let i = Instruction::parse("NORM", None, None).unwrap().unwrap();
+        assert_eq!(i.size(), Some(1));
+        assert_eq!(i.expressions(), None);
+        assert_eq!(i.to_string(), "op: 0xC8");
+    }
+
+    #[test]
+    fn float_instructions() {
+        for (operator, opcode) in [
+            ("ADDF", 0x58),
+            ("COMPF", 0x88),
+            ("DIVF", 0x64),
+            ("LDF", 0x70),
+            ("MULF", 0x60),
+            ("STF", 0x80),
+            ("SUBF", 0x5C),
+        ] {
+            match Instruction::parse(operator, Some("VALUE"), None) {
+                Some(Ok(Instruction::Format34(f))) => {
+                    assert_eq!(f.opcode, opcode, "{operator}");
+                    assert!(!f.is_format4());
+                }
+                other => panic!("Expected format 3 instruction, got {:?}", other),
+            }
+        }
+
+        let i = Instruction::parse("+LDF", Some("VALUE"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(i.size(), Some(4));
+    }
+
+    fn parse_format2(operator: &str, operand: &str) -> Result<(u8, u8, u8), String> {
+        match Instruction::parse(operator, Some(operand), None) {
+            Some(Ok(Instruction::Format2(f))) => Ok((
+                f.opcode,
+                f.register1.eval().unwrap() as u8,
+                f.register2.eval().unwrap() as u8,
+            )),
+            Some(Err(e)) => Err(e),
+            other => panic!("Expected format 2 instruction, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn format2_registers() {
+        assert_eq!(parse_format2("COMPR", "A,S"), Ok((0xA0, 0, 4)));
+        assert_eq!(parse_format2("RMO", "SW,PC"), Ok((0xAC, 9, 8)));
+        assert_eq!(parse_format2("TIXR", "T"), Ok((0xB8, 5, 0)));
+        assert_eq!(
+            parse_format2("ADDR", "A,5"),
+            Err("Invalid register \"5\"".to_string())
+        );
+        assert_eq!(
+            parse_format2("CLEAR", "A,X"),
+            Err("Invalid number of operands".to_string())
+        );
+    }
+
+    #[test]
+    fn format2_shift() {
+        assert_eq!(parse_format2("SHIFTL", "T,1"), Ok((0xA4, 5, 0)));
+        assert_eq!(parse_format2("SHIFTR", "A,16"), Ok((0xA8, 0, 15)));
+        assert_eq!(
+            parse_format2("SHIFTL", "T,0"),
+            Err("Number 0 out of range 1..16".to_string())
+        );
+        assert_eq!(
+            parse_format2("SHIFTL", "T,S"),
+            Err("Expected a number, found \"S\"".to_string())
+        );
+        // symbolic counts are checked once resolved
+        match Instruction::parse("SHIFTL", Some("T,N"), None) {
+            Some(Ok(Instruction::Format2(f))) => assert_eq!(f.register2.to_string(), "N-1"),
+            other => panic!("Expected format 2 instruction, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn format2_svc() {
+        assert_eq!(parse_format2("SVC", "7"), Ok((0xB0, 7, 0)));
+        assert_eq!(
+            parse_format2("SVC", "16"),
+            Err("Number 16 out of range 0..15".to_string())
+        );
+        assert_eq!(
+            parse_format2("SVC", "A"),
+            Err("Expected a number, found \"A\"".to_string())
+        );
+    }
+
+    #[test]
+    fn system_instructions() {
+        for (operator, opcode) in [("LPS", 0xD0), ("SSK", 0xEC), ("STI", 0xD4), ("STSW", 0xE8)] {
+            match Instruction::parse(operator, Some("ADDR"), None) {
+                Some(Ok(Instruction::Format34(f))) => assert_eq!(f.opcode, opcode, "{operator}"),
+                other => panic!("Expected format 3 instruction, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn format1_rejects_operand() {
+        let err = Instruction::parse("FIX", Some("A"), None).unwrap();
+        assert_eq!(err, Err("Unexpected operand".to_string()));
+    }
+
+    #[test]
+    fn format1_rejects_extended() {
+        let err = Instruction::parse("+SIO", None, None).unwrap();
+        assert_eq!(
+            err,
+            Err("Format 1 instruction cannot be extended".to_string())
+        );
+    }
+}
@@ -1,14 +1,18 @@
+pub mod charset;
 pub mod directive;
 pub mod expression;
 pub mod instruction;
+pub mod optab;
 pub mod record;
 pub mod tokenize;
 
 use std::fmt::{Display, Formatter};
 
+use charset::*;
 use directive::*;
 use expression::*;
 use instruction::*;
+use optab::*;
 use record::*;
 use tokenize::*;
 
@@ -35,6 +39,11 @@ pub trait FrameLike {
     fn expressions(&self) -> Option<Vec<&Expression>> {
         None
     }
+
+    /// Mutably access the expressions of a frame if any.
+    fn expressions_mut(&mut self) -> Option<Vec<&mut Expression>> {
+        None
+    }
 }
 
 /// Frame should be immutable
@@ -70,7 +79,30 @@ impl Frame {
     }
 
     pub fn from_source(source: &str, line: u32) -> Result<Option<Frame>, String> {
-        let sources = vec![FrameSource::Source(source.to_string(), line)];
+        Frame::from_source_with(source, line, OpTable::builtin(), Charset::default())
+    }
+
+    /// Parses a source line, looking up instructions in the given operation code table
+    /// and encoding character constants in the given character set.
+    pub fn from_source_with(
+        source: &str,
+        line: u32,
+        optab: &OpTable,
+        charset: Charset,
+    ) -> Result<Option<Frame>, String> {
+        let location = vec![Location::new(None, line)];
+        Frame::from_source_at(source, location, optab, charset)
+    }
+
+    /// Parses a source line found at the given location,
+    /// the innermost location first followed by the lines it was included from.
+    pub fn from_source_at(
+        source: &str,
+        location: Vec<Location>,
+        optab: &OpTable,
+        charset: Charset,
+    ) -> Result<Option<Frame>, String> {
+        let sources = vec![FrameSource::Source(source.to_string(), location)];
         let tokens = tokenize(source)?;
 
         match tokens.len() {
@@ -79,7 +111,7 @@ impl Frame {
                 let operator = tokens[0].as_str();
                 let operand = None;
 
-                let inner = Frame::parse_inner(operator, operand, None);
+                let inner = Frame::parse_inner_with(operator, operand, None, optab, charset);
                 if let Ok(inner) = inner {
                     Ok(Some(Frame {
                         sources,
@@ -100,16 +132,31 @@ impl Frame {
                 let operator = tokens[0].as_str();
                 let operand = Some(tokens[1].as_str());
 
-                let mut inner = Frame::parse_inner(operator, operand, label.as_deref());
+                let mut inner =
+                    Frame::parse_inner_with(operator, operand, label.as_deref(), optab, charset);
                 // case 2: label and operator
                 if inner.is_err() {
                     #[cfg(debug_assertions)]
-                    println!("Failed to parse as operator and operand: {}, fallback to label and operator", &inner.err().unwrap());
+                    println!("Failed to parse as operator and operand: {}, fallback to label and operator", inner.as_ref().unwrap_err());
 
-                    label = Some(tokens[0].as_str().to_string());
+                    let label_operator = Some(tokens[0].as_str().to_string());
                     let operator = tokens[1].as_str();
                     let operand = None;
-                    inner = Frame::parse_inner(operator, operand, label.as_deref());
+                    let fallback = Frame::parse_inner_with(
+                        operator,
+                        operand,
+                        label_operator.as_deref(),
+                        optab,
+                        charset,
+                    );
+                    // a known operator with an invalid operand reports the operand error
+                    let unknown = inner
+                        .as_ref()
+                        .is_err_and(|e| e.starts_with("Invalid operator"));
+                    if fallback.is_ok() || unknown {
+                        label = label_operator;
+                        inner = fallback;
+                    }
                 }
 
                 if let Ok(inner) = inner {
@@ -131,7 +178,8 @@ impl Frame {
                 let operator = tokens[1].as_str();
                 let operand = Some(tokens[2].as_str());
 
-                let inner = Frame::parse_inner(operator, operand, label.as_deref());
+                let inner =
+                    Frame::parse_inner_with(operator, operand, label.as_deref(), optab, charset);
                 if let Ok(inner) = inner {
                     Ok(Some(Frame {
                         sources,
@@ -155,11 +203,27 @@ impl Frame {
         operand: Option<&str>,
         label: Option<&str>,
     ) -> Result<FrameInner, String> {
-        if let Some(result) = Instruction::parse(operator, operand, label) {
+        Frame::parse_inner_with(
+            operator,
+            operand,
+            label,
+            OpTable::builtin(),
+            Charset::default(),
+        )
+    }
+
+    pub fn parse_inner_with(
+        operator: &str,
+        operand: Option<&str>,
+        label: Option<&str>,
+        optab: &OpTable,
+        charset: Charset,
+    ) -> Result<FrameInner, String> {
+        if let Some(result) = Instruction::parse_with(optab, charset, operator, operand) {
             return result.map(FrameInner::Instruction);
         }
 
-        if let Some(result) = Directive::parse(operator, operand, label) {
+        if let Some(result) = Directive::parse_with(charset, operator, operand, label) {
             return result.map(FrameInner::Directive);
         }
 
@@ -174,6 +238,15 @@ impl Frame {
             FrameInner::ObjectRecord(r) => r.expressions(),
         }
     }
+
+    /// get mutable expressions from frame
+    pub fn expressions_mut(&mut self) -> Option<Vec<&mut Expression>> {
+        match &mut self.inner {
+            FrameInner::Instruction(i) => i.expressions_mut(),
+            FrameInner::Directive(d) => d.expressions_mut(),
+            FrameInner::ObjectRecord(r) => r.expressions_mut(),
+        }
+    }
 }
 
 impl Display for Frame {
@@ -184,19 +257,59 @@ impl Display for Frame {
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum FrameSource {
-    Source(String, u32),
+    /// A source line and where it was found,
+    /// followed by the lines it was included from or expanded from.
+    Source(String, Vec<Location>),
     Frame(Frame),
 }
 
 impl Display for FrameSource {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            FrameSource::Source(source, line) => write!(f, "Source {source} (Line {line})"),
+            FrameSource::Source(source, location) => {
+                write!(f, "Source {source} ({})", location[0])?;
+                for parent in &location[1..] {
+                    match &parent.expanding {
+                        Some(name) => write!(f, "\n\texpanded from {name} at {parent}")?,
+                        None => write!(f, "\n\tincluded from {parent}")?,
+                    }
+                }
+                Ok(())
+            }
             FrameSource::Frame(frame) => write!(f, "{frame}"),
         }
     }
 }
 
+/// A line in a source file,
+/// the file is None for the source given to the assembler as a string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: Option<String>,
+    pub line: u32,
+    /// The macro or repetition expanded at this line, when it leads to an expanded line.
+    pub expanding: Option<String>,
+}
+
+impl Location {
+    pub fn new(file: Option<String>, line: u32) -> Location {
+        Location {
+            file,
+            line,
+            expanding: None,
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{file}:{line}", line = self.line),
+            None => write!(f, "Line {line}", line = self.line),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum FrameInner {
     Instruction(Instruction),
@@ -0,0 +1,469 @@
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+/// The instruction format of an operation.
+/// Format 3 operations can also be assembled as format 4 with the `+` prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpFormat {
+    Format1,
+    Format2,
+    Format34,
+}
+
+/// The shape of the operand field an operation accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandSignature {
+    /// No operand, e.g. `FIX` or `RSUB`.
+    None,
+    /// A single register, e.g. `CLEAR r1`.
+    Register,
+    /// Two registers, e.g. `ADDR r1,r2`.
+    RegisterRegister,
+    /// A register and a shift count `1..=16`, e.g. `SHIFTL r1,n`.
+    RegisterCount,
+    /// A number `0..=15`, e.g. `SVC n`.
+    Number,
+    /// A memory operand, e.g. `LDA m`.
+    Memory,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpCode {
+    pub mnemonic: String,
+    pub opcode: u8,
+    pub format: OpFormat,
+    pub operands: OperandSignature,
+    /// Only available on SIC/XE, not on the standard SIC machine.
+    pub xe_only: bool,
+    /// Can only be executed in supervisor mode.
+    pub privileged: bool,
+}
+
+impl OpCode {
+    pub fn new(
+        mnemonic: &str,
+        opcode: u8,
+        format: OpFormat,
+        operands: OperandSignature,
+        xe_only: bool,
+        privileged: bool,
+    ) -> OpCode {
+        OpCode {
+            mnemonic: mnemonic.to_string(),
+            opcode,
+            format,
+            operands,
+            xe_only,
+            privileged,
+        }
+    }
+
+    /// Checks that the operand signature and opcode fit the instruction format.
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = match self.format {
+            OpFormat::Format1 => self.operands == OperandSignature::None,
+            OpFormat::Format2 => matches!(
+                self.operands,
+                OperandSignature::Register
+                    | OperandSignature::RegisterRegister
+                    | OperandSignature::RegisterCount
+                    | OperandSignature::Number
+            ),
+            OpFormat::Format34 => matches!(
+                self.operands,
+                OperandSignature::None | OperandSignature::Memory
+            ),
+        };
+        if !valid {
+            return Err(format!("Invalid operand signature for {}", self.mnemonic));
+        }
+
+        // the lowest 2 bits of a format 3/4 opcode are taken by the n and i flags
+        if self.format == OpFormat::Format34 && self.opcode & 0b11 != 0 {
+            return Err(format!(
+                "Opcode {:02X} of format 3/4 instruction must be a multiple of 4",
+                self.opcode
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_definition(line: &str) -> Result<(OpCode, bool), String> {
+    let mut fields = line.split_whitespace();
+    let mnemonic = fields.next().ok_or("Missing mnemonic".to_string())?;
+    if !mnemonic.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("Invalid mnemonic \"{mnemonic}\""));
+    }
+
+    let opcode = fields.next().ok_or("Missing opcode".to_string())?;
+    let opcode = u8::from_str_radix(opcode.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("Invalid opcode \"{opcode}\""))?;
+
+    let format = match fields.next() {
+        Some("1") => OpFormat::Format1,
+        Some("2") => OpFormat::Format2,
+        Some("3") | Some("4") | Some("3/4") => OpFormat::Format34,
+        Some(f) => return Err(format!("Invalid format \"{f}\"")),
+        None => return Err("Missing format".to_string()),
+    };
+
+    let mut operands = match format {
+        OpFormat::Format1 => OperandSignature::None,
+        OpFormat::Format2 => OperandSignature::RegisterRegister,
+        OpFormat::Format34 => OperandSignature::Memory,
+    };
+    let mut xe_only = false;
+    let mut privileged = false;
+    let mut is_override = false;
+    for field in fields {
+        match field {
+            "none" => operands = OperandSignature::None,
+            "r1" => operands = OperandSignature::Register,
+            "r1,r2" => operands = OperandSignature::RegisterRegister,
+            "r1,n" => operands = OperandSignature::RegisterCount,
+            "n" => operands = OperandSignature::Number,
+            "m" => operands = OperandSignature::Memory,
+            "xe" => xe_only = true,
+            "privileged" => privileged = true,
+            "override" => is_override = true,
+            _ => return Err(format!("Unknown field \"{field}\"")),
+        }
+    }
+
+    let op = OpCode::new(mnemonic, opcode, format, operands, xe_only, privileged);
+    Ok((op, is_override))
+}
+
+impl Display for OpCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = match self.format {
+            OpFormat::Format1 => "1",
+            OpFormat::Format2 => "2",
+            OpFormat::Format34 => "3/4",
+        };
+        write!(
+            f,
+            "{:<6}\t{:02X}\t{}{}{}",
+            self.mnemonic,
+            self.opcode,
+            format,
+            if self.xe_only { "\tX" } else { "" },
+            if self.privileged { "\tP" } else { "" }
+        )
+    }
+}
+
+/// The operation code table (OPTAB).
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpTable {
+    ops: Vec<OpCode>,
+}
+
+impl OpTable {
+    /// The standard SIC/XE instruction set.
+    pub fn builtin() -> &'static OpTable {
+        static BUILTIN: OnceLock<OpTable> = OnceLock::new();
+        BUILTIN.get_or_init(|| {
+            use OpFormat::*;
+            use OperandSignature::*;
+
+            let ops = vec![
+                // format 1
+                OpCode::new("FIX", 0xC4, Format1, None, true, false),
+                OpCode::new("FLOAT", 0xC0, Format1, None, true, false),
+                OpCode::new("HIO", 0xF4, Format1, None, true, true),
+                OpCode::new("NORM", 0xC8, Format1, None, true, false),
+                OpCode::new("SIO", 0xF0, Format1, None, true, true),
+                OpCode::new("TIO", 0xF8, Format1, None, true, true),
+                // format 2
+                OpCode::new("ADDR", 0x90, Format2, RegisterRegister, true, false),
+                OpCode::new("CLEAR", 0xB4, Format2, Register, true, false),
+                OpCode::new("COMPR", 0xA0, Format2, RegisterRegister, true, false),
+                OpCode::new("DIVR", 0x9C, Format2, RegisterRegister, true, false),
+                OpCode::new("MULR", 0x98, Format2, RegisterRegister, true, false),
+                OpCode::new("RMO", 0xAC, Format2, RegisterRegister, true, false),
+                OpCode::new("SHIFTL", 0xA4, Format2, RegisterCount, true, false),
+                OpCode::new("SHIFTR", 0xA8, Format2, RegisterCount, true, false),
+                OpCode::new("SUBR", 0x94, Format2, RegisterRegister, true, false),
+                OpCode::new("SVC", 0xB0, Format2, Number, true, false),
+                OpCode::new("TIXR", 0xB8, Format2, Register, true, false),
+                // format 3/4
+                OpCode::new("ADD", 0x18, Format34, Memory, false, false),
+                OpCode::new("ADDF", 0x58, Format34, Memory, true, false),
+                OpCode::new("AND", 0x40, Format34, Memory, false, false),
+                OpCode::new("COMP", 0x28, Format34, Memory, false, false),
+                OpCode::new("COMPF", 0x88, Format34, Memory, true, false),
+                OpCode::new("DIV", 0x24, Format34, Memory, false, false),
+                OpCode::new("DIVF", 0x64, Format34, Memory, true, false),
+                OpCode::new("J", 0x3C, Format34, Memory, false, false),
+                OpCode::new("JEQ", 0x30, Format34, Memory, false, false),
+                OpCode::new("JGT", 0x34, Format34, Memory, false, false),
+                OpCode::new("JLT", 0x38, Format34, Memory, false, false),
+                OpCode::new("JSUB", 0x48, Format34, Memory, false, false),
+                OpCode::new("LDA", 0x00, Format34, Memory, false, false),
+                OpCode::new("LDB", 0x68, Format34, Memory, true, false),
+                OpCode::new("LDCH", 0x50, Format34, Memory, false, false),
+                OpCode::new("LDF", 0x70, Format34, Memory, true, false),
+                OpCode::new("LDL", 0x08, Format34, Memory, false, false),
+                OpCode::new("LDS", 0x6C, Format34, Memory, true, false),
+                OpCode::new("LDT", 0x74, Format34, Memory, true, false),
+                OpCode::new("LDX", 0x04, Format34, Memory, false, false),
+                OpCode::new("LPS", 0xD0, Format34, Memory, true, true),
+                OpCode::new("MUL", 0x20, Format34, Memory, false, false),
+                OpCode::new("MULF", 0x60, Format34, Memory, true, false),
+                OpCode::new("OR", 0x44, Format34, Memory, false, false),
+                OpCode::new("RD", 0xD8, Format34, Memory, false, true),
+                OpCode::new("RSUB", 0x4C, Format34, None, false, false),
+                OpCode::new("SSK", 0xEC, Format34, Memory, true, true),
+                OpCode::new("STA", 0x0C, Format34, Memory, false, false),
+                OpCode::new("STB", 0x78, Format34, Memory, true, false),
+                OpCode::new("STCH", 0x54, Format34, Memory, false, false),
+                OpCode::new("STF", 0x80, Format34, Memory, true, false),
+                OpCode::new("STI", 0xD4, Format34, Memory, true, true),
+                OpCode::new("STL", 0x14, Format34, Memory, false, false),
+                OpCode::new("STS", 0x7C, Format34, Memory, true, false),
+                OpCode::new("STSW", 0xE8, Format34, Memory, false, true),
+                OpCode::new("STT", 0x84, Format34, Memory, true, false),
+                OpCode::new("STX", 0x10, Format34, Memory, false, false),
+                OpCode::new("SUB", 0x1C, Format34, Memory, false, false),
+                OpCode::new("SUBF", 0x5C, Format34, Memory, true, false),
+                OpCode::new("TD", 0xE0, Format34, Memory, false, true),
+                OpCode::new("TIX", 0x2C, Format34, Memory, false, false),
+                OpCode::new("WD", 0xDC, Format34, Memory, false, true),
+            ];
+
+            OpTable { ops }
+        })
+    }
+
+    /// Looks up a mnemonic in the standard SIC/XE instruction set.
+    pub fn lookup(mnemonic: &str) -> Option<&'static OpCode> {
+        OpTable::builtin().get(mnemonic)
+    }
+
+    /// Looks up a mnemonic in this table.
+    pub fn get(&self, mnemonic: &str) -> Option<&OpCode> {
+        self.ops.iter().find(|op| op.mnemonic == mnemonic)
+    }
+
+    /// Enumerates the instruction set in table order.
+    pub fn iter(&self) -> impl Iterator<Item = &OpCode> {
+        self.ops.iter()
+    }
+
+    /// Adds an operation to the table.
+    /// Returns an error if the mnemonic is already defined.
+    pub fn insert(&mut self, op: OpCode) -> Result<(), String> {
+        if self.get(&op.mnemonic).is_some() {
+            return Err(format!(
+                "Instruction \"{}\" is already defined",
+                op.mnemonic
+            ));
+        }
+
+        op.validate()?;
+        self.ops.push(op);
+        Ok(())
+    }
+
+    /// Adds an operation to the table, replacing the definition with the same mnemonic if any.
+    pub fn replace(&mut self, op: OpCode) -> Result<(), String> {
+        op.validate()?;
+        match self.ops.iter_mut().find(|o| o.mnemonic == op.mnemonic) {
+            Some(existing) => *existing = op,
+            None => self.ops.push(op),
+        }
+        Ok(())
+    }
+
+    /// Merges instruction definitions into the table.
+    ///
+    /// Each line defines one instruction in the form
+    /// `MNEMONIC OPCODE FORMAT [OPERANDS] [FLAGS...]`, for example:
+    ///
+    /// ```text
+    /// # mnemonic  opcode  format  operands  flags
+    /// HALT        FC      3       none
+    /// PUSH        C4      2       r1        xe
+    /// LDA         00      3       m         override
+    /// ```
+    ///
+    /// - `OPCODE` is hexadecimal, optionally prefixed with `0x`.
+    /// - `FORMAT` is `1`, `2` or `3` (format 3 instructions can be extended to format 4).
+    /// - `OPERANDS` is one of `none`, `r1`, `r1,r2`, `r1,n`, `n` or `m`;
+    ///   it defaults to `none` for format 1 and `m` for format 3.
+    /// - `FLAGS` are `xe` (SIC/XE only), `privileged` and `override`,
+    ///   the latter allows redefining an existing mnemonic.
+    ///
+    /// Blank lines and comments starting with `#` or `.` are ignored.
+    pub fn load_definitions(&mut self, definitions: &str) -> Result<(), String> {
+        for (i, line) in definitions.lines().enumerate() {
+            let line = line.split(['#', '.']).next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (op, is_override) = parse_definition(line)
+                .map_err(|e| format!("{e}\n\tat Definition {line} (Line {})", i + 1))?;
+            if is_override {
+                self.replace(op)
+            } else {
+                self.insert(op)
+            }
+            .map_err(|e| format!("{e}\n\tat Definition {line} (Line {})", i + 1))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl Default for OpTable {
+    fn default() -> Self {
+        OpTable::builtin().clone()
+    }
+}
+
+impl Display for OpTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for op in &self.ops {
+            writeln!(f, "{op}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let lda = OpTable::lookup("LDA").unwrap();
+        assert_eq!(lda.opcode, 0x00);
+        assert_eq!(lda.format, OpFormat::Format34);
+        assert_eq!(lda.operands, OperandSignature::Memory);
+        assert!(!lda.xe_only);
+        assert!(!lda.privileged);
+
+        let sio = OpTable::lookup("SIO").unwrap();
+        assert_eq!(sio.format, OpFormat::Format1);
+        assert!(sio.xe_only);
+        assert!(sio.privileged);
+
+        assert_eq!(OpTable::lookup("LDZ"), None);
+    }
+
+    #[test]
+    fn enumerate() {
+        let optab = OpTable::builtin();
+        assert_eq!(optab.len(), 59);
+
+        let sic = optab.iter().filter(|op| !op.xe_only).count();
+        assert_eq!(sic, 26);
+
+        // mnemonics and (opcode, format) pairs are unique
+        for (i, a) in optab.iter().enumerate() {
+            for b in optab.iter().skip(i + 1) {
+                assert_ne!(a.mnemonic, b.mnemonic);
+                assert!(a.opcode != b.opcode || a.format != b.format);
+            }
+        }
+    }
+
+    #[test]
+    fn insert_and_replace() {
+        let mut optab = OpTable::default();
+        let halt = OpCode::new(
+            "HALT",
+            0xFC,
+            OpFormat::Format34,
+            OperandSignature::None,
+            false,
+            false,
+        );
+        optab.insert(halt.clone()).unwrap();
+        assert_eq!(optab.get("HALT"), Some(&halt));
+        assert_eq!(OpTable::lookup("HALT"), None);
+
+        let lda = OpCode::new(
+            "LDA",
+            0xFC,
+            OpFormat::Format34,
+            OperandSignature::Memory,
+            false,
+            false,
+        );
+        assert_eq!(
+            optab.insert(lda.clone()),
+            Err("Instruction \"LDA\" is already defined".to_string())
+        );
+        optab.replace(lda).unwrap();
+        assert_eq!(optab.get("LDA").unwrap().opcode, 0xFC);
+        assert_eq!(optab.len(), OpTable::builtin().len() + 1);
+    }
+
+    #[test]
+    fn load_definitions() {
+        let mut optab = OpTable::default();
+        optab
+            .load_definitions(
+                "# custom instructions\n\
+                 HALT  FC  3  none\n\
+                 \n\
+                 PUSH  0xC4  2  r1  xe  . push a register\n\
+                 TRAP  F8  1  privileged override\n",
+            )
+            .unwrap();
+
+        let halt = optab.get("HALT").unwrap();
+        assert_eq!(halt.opcode, 0xFC);
+        assert_eq!(halt.format, OpFormat::Format34);
+        assert_eq!(halt.operands, OperandSignature::None);
+
+        let push = optab.get("PUSH").unwrap();
+        assert_eq!(push.format, OpFormat::Format2);
+        assert_eq!(push.operands, OperandSignature::Register);
+        assert!(push.xe_only);
+
+        let trap = optab.get("TRAP").unwrap();
+        assert_eq!(trap.operands, OperandSignature::None);
+        assert!(trap.privileged);
+    }
+
+    #[test]
+    fn load_definitions_errors() {
+        let mut optab = OpTable::default();
+        assert_eq!(
+            optab.load_definitions("HALT FC 3\nLDA 00 3"),
+            Err(
+                "Instruction \"LDA\" is already defined\n\tat Definition LDA 00 3 (Line 2)"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            optab.load_definitions("BAD 01 3"),
+            Err("Opcode 01 of format 3/4 instruction must be a multiple of 4\n\tat Definition BAD 01 3 (Line 1)".to_string())
+        );
+        assert_eq!(
+            optab.load_definitions("BAD 10 2 m"),
+            Err(
+                "Invalid operand signature for BAD\n\tat Definition BAD 10 2 m (Line 1)"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            optab.load_definitions("BAD ZZ 1"),
+            Err("Invalid opcode \"ZZ\"\n\tat Definition BAD ZZ 1 (Line 1)".to_string())
+        );
+    }
+}
@@ -24,6 +24,9 @@ pub struct TextRecord {
     pub start: u32,
     pub length: u32,
     pub data: Vec<u8>,
+    /// Texts are only merged into one T record within a segment,
+    /// a new segment begins whenever the program switches blocks with `USE`.
+    pub segment: usize,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -4,8 +4,8 @@ pub fn tokenize(line: &str) -> Result<Vec<String>, String> {
     let mut matched: Vec<String> = vec![];
     let mut current = String::new();
 
-    let mut prev = '\0';
     let mut literal = false;
+    let mut escaped = false;
 
     for c in line.chars() {
         if !literal {
@@ -15,7 +15,6 @@ pub fn tokenize(line: &str) -> Result<Vec<String>, String> {
                     current.clear();
                 }
 
-                prev = c;
                 continue;
             }
 
@@ -29,14 +28,17 @@ pub fn tokenize(line: &str) -> Result<Vec<String>, String> {
 
             current.push(c);
         } else {
-            if c == '\'' && prev != '\\' {
+            // a doubled quote simply closes and reopens the literal
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '\'' {
                 literal = false;
             }
 
             current.push(c);
         }
-
-        prev = c;
     }
 
     if literal {
@@ -112,6 +114,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn literal_escapes() {
+        let res = tokenize("MYVAR   BYTE    C'IT''S A \\\\'").unwrap();
+        assert_eq!(
+            res,
+            vec![
+                "MYVAR".to_string(),
+                "BYTE".to_string(),
+                "C'IT''S A \\\\'".to_string()
+            ]
+        );
+    }
+
     #[test]
     fn too_many_token() {
         let res = tokenize("ABC DEF GHI JKL").unwrap();
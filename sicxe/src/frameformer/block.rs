@@ -1,85 +1,115 @@
 use crate::frame::*;
-use std::collections::HashMap;
+use std::fmt::Display;
 
 pub const DEFAULT_BLOCK_NAME: &str = "";
 
-pub fn rearrange_blocks(programs: Vec<Frame>) -> Vec<Frame> {
-    let mut block_frames = HashMap::<String, Vec<Frame>>::new();
-    let mut block_order = Vec::<String>::new();
-    let mut current_block = DEFAULT_BLOCK_NAME.to_string();
-
-    let start_frame = programs.first().unwrap();
-    let end_frame = programs.last().unwrap();
-    let extdefs = programs
-        .iter()
-        .filter(|frame| {
-            matches!(
-                frame.inner,
-                FrameInner::Directive(directive::Directive::EXTDEF(_))
-            )
-        })
-        .cloned()
-        .collect::<Vec<Frame>>();
-    let extrefs = programs
-        .iter()
-        .filter(|frame| {
-            matches!(
-                frame.inner,
-                FrameInner::Directive(directive::Directive::EXTREF(_))
-            )
-        })
-        .cloned()
-        .collect::<Vec<Frame>>();
-
-    let rerrangables = programs
-        .iter()
-        .filter(|frame| match frame.inner {
-            FrameInner::Directive(ref d) => !matches!(
-                d,
-                directive::Directive::START(_)
-                    | directive::Directive::END(_)
-                    | directive::Directive::EXTDEF(_)
-                    | directive::Directive::EXTREF(_)
-            ),
-            _ => true,
-        })
-        .cloned()
-        .collect::<Vec<Frame>>();
-
-    #[cfg(debug_assertions)]
-    println!("current block: {}", &current_block);
-
-    for frame in rerrangables {
-        let switch_to = match frame.inner {
-            FrameInner::Directive(directive::Directive::USE(ref u)) => Some(u.name.clone()),
-            _ => None,
-        };
+/// A program block, switched to with `USE name`.
+/// The blocks are placed one after another in order of their numbers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub name: String,
+    pub number: usize,
+    pub start: u32,
+    pub length: u32,
+}
 
-        if let Some(name) = switch_to {
-            current_block = name;
-            #[cfg(debug_assertions)]
-            println!("current block: {}", &current_block);
+impl Block {
+    /// The name of the block, `(default)` for the unnamed default block.
+    pub fn display_name(&self) -> &str {
+        if self.name == DEFAULT_BLOCK_NAME {
+            "(default)"
         } else {
-            if !block_frames.contains_key(&current_block) {
-                block_frames.insert(current_block.clone(), Vec::new());
-            }
-            if !block_order.contains(&current_block) {
-                block_order.push(current_block.clone());
+            &self.name
+        }
+    }
+}
+
+/// The program blocks of a control section.
+/// The default block is always number 0, the others are numbered in order of first use.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockTable {
+    blocks: Vec<Block>,
+}
+
+impl BlockTable {
+    /// Collects the blocks used in the program, the start and length are not known yet.
+    pub fn from_program(program: &[Frame]) -> BlockTable {
+        let mut table = BlockTable {
+            blocks: vec![Block {
+                name: DEFAULT_BLOCK_NAME.to_string(),
+                number: 0,
+                start: 0,
+                length: 0,
+            }],
+        };
+
+        for frame in program {
+            if let FrameInner::Directive(directive::Directive::USE(ref u)) = frame.inner {
+                if table.number(&u.name).is_none() {
+                    table.blocks.push(Block {
+                        name: u.name.clone(),
+                        number: table.blocks.len(),
+                        start: 0,
+                        length: 0,
+                    });
+                }
             }
+        }
 
-            block_frames.get_mut(&current_block).unwrap().push(frame);
+        table
+    }
+
+    /// Places the blocks one after another from the start of the section.
+    pub fn layout(&mut self, start: u32, lengths: &[u32]) {
+        let mut address = start;
+        for (block, length) in self.blocks.iter_mut().zip(lengths) {
+            block.start = address;
+            block.length = *length;
+            address += length;
         }
     }
 
-    let mut frames = vec![start_frame.clone()];
-    frames.append(&mut extdefs.clone());
-    frames.append(&mut extrefs.clone());
-    for block in block_order {
-        frames.append(&mut block_frames.get_mut(&block).unwrap().clone());
+    pub fn number(&self, name: &str) -> Option<usize> {
+        self.blocks.iter().position(|block| block.name == name)
+    }
+
+    pub fn get(&self, number: usize) -> Option<&Block> {
+        self.blocks.get(number)
     }
-    frames.push(end_frame.clone());
 
-    frames
+    pub fn iter(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// The total length of all blocks.
+    pub fn length(&self) -> u32 {
+        self.blocks.iter().map(|block| block.length).sum()
+    }
+}
+
+impl Display for BlockTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Block name  Number  Address  Length")?;
+        for block in &self.blocks {
+            writeln!(
+                f,
+                "{:<10}  {:<6}  {:06X}   {:06X}",
+                block.display_name(),
+                block.number,
+                block.start,
+                block.length
+            )?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -98,33 +128,19 @@ mod tests {
             }
         }
 
-        let frames = rearrange_blocks(frames);
-        dbg!(&frames);
-
-        assert_eq!(frames.len(), 45);
-        match frames[13].inner {
-            FrameInner::Instruction(ref i) => {
-                match i {
-                    instruction::Instruction::Format34(ref i) => {
-                        // J
-                        assert_eq!(i.opcode, 0x3C);
-                    }
-                    _ => panic!("Expected format 3/4 instruction"),
-                }
-            }
-            _ => panic!("Expected instruction"),
-        }
-        match frames[14].inner {
-            FrameInner::Instruction(ref i) => {
-                match i {
-                    instruction::Instruction::Format2(ref i) => {
-                        // CLEAR
-                        assert_eq!(i.opcode, 0xB4);
-                    }
-                    _ => panic!("Expected format 2 instruction"),
-                }
-            }
-            _ => panic!("Expected instruction"),
-        }
+        let mut blocks = BlockTable::from_program(&frames);
+        let names = blocks.iter().map(|b| b.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["", "CDATA", "CBLKS"]);
+
+        blocks.layout(0, &[0x66, 0x0B, 0x1000]);
+        assert_eq!(blocks.get(2).unwrap().start, 0x71);
+        assert_eq!(blocks.length(), 0x1071);
+        assert_eq!(
+            blocks.to_string(),
+            "Block name  Number  Address  Length\n\
+             (default)   0       000000   000066\n\
+             CDATA       1       000066   00000B\n\
+             CBLKS       2       000071   001000\n"
+        );
     }
 }
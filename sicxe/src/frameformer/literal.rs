@@ -1,87 +1,237 @@
+use crate::assembler::AssemblerOptions;
 use crate::frame::{
-    directive::{literal_to_data, Directive, BYTE},
-    expression::parse,
-    instruction::Format34,
+    charset::Charset,
+    directive::{literal_to_data, Directive, BYTE, EQU, WORD},
+    expression::{parse, parse_constant, Expression},
     *,
 };
-use std::collections::HashMap;
 
-pub fn dump_literals(program: Vec<Frame>) -> Vec<Frame> {
+/// The order in which the literals of a pool are placed at `LTORG` or `END`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LiteralPlacement {
+    /// In order of first use.
+    #[default]
+    FirstUse,
+    /// The longest literals first, keeping the longer ones aligned.
+    LargestFirst,
+    /// Sorted by the literal, e.g. `C'EOF'` before `X'05'`.
+    ByName,
+}
+
+/// A literal waiting in the pool.
+struct Literal {
+    value: String,
+    data: Vec<u8>,
+    label: String,
+    /// The label of the use site of a locctr literal `=*`, whose pool entry is a word
+    /// holding the address of the instruction using it.
+    site: Option<String>,
+}
+
+impl Literal {
+    fn size(&self) -> usize {
+        match self.site {
+            Some(_) => 3,
+            None => self.data.len(),
+        }
+    }
+}
+
+pub fn dump_literals(
+    program: Vec<Frame>,
+    options: &AssemblerOptions,
+) -> Result<Vec<Frame>, String> {
     let mut program = program;
-    let mut literal_pool = HashMap::<String, String>::new();
+    let mut literal_pool = Vec::<Literal>::new();
 
     let mut literal_count = 0;
     let mut i = 0;
     while i < program.len() {
         let frame = &mut program[i];
-        match &mut frame.inner {
-            FrameInner::Directive(d) => match d {
-                Directive::LTORG(_) => {
-                    let bytes = dump_pool(&literal_pool, frame);
-                    program.splice(i..=i, bytes);
-                    literal_pool.clear();
-                }
-                Directive::END(_) => {
-                    let bytes = dump_pool(&literal_pool, frame);
-                    program.splice(i..i, bytes);
-                    literal_pool.clear();
-                }
-                _ => {}
-            },
-            FrameInner::Instruction(instruction::Instruction::Format34(ref mut i)) => {
-                if let Some(literal) = get_literal(i) {
-                    let reference =
-                        get_literal_reference(&mut literal_pool, literal, &mut literal_count);
-                    i.value = Box::new(parse(&reference).unwrap());
-                }
+        let source = frame.sources()[0].to_string();
+        match frame.inner {
+            FrameInner::Directive(Directive::LTORG(_)) => {
+                let bytes = dump_pool(&mut literal_pool, frame, options);
+                program.splice(i..=i, bytes);
+                i += 1;
+                continue;
+            }
+            FrameInner::Directive(Directive::END(_)) => {
+                let bytes = dump_pool(&mut literal_pool, frame, options);
+                program.splice(i..i, bytes);
+                i += 1;
+                continue;
             }
             _ => {}
         }
 
+        // literals are allowed as the operand of format 3/4 instructions and WORD
+        let allowed = matches!(
+            frame.inner,
+            FrameInner::Instruction(instruction::Instruction::Format34(_))
+                | FrameInner::Directive(Directive::WORD(_))
+        );
+        let mut sites = vec![];
+        for expr in frame.expressions_mut().unwrap_or_default() {
+            let Expression::Literal(ref literal) = *expr else {
+                continue;
+            };
+            if !allowed {
+                return Err(format!("Literals are not allowed here\n\tat {}", source));
+            }
+
+            let reference = if literal == "*" {
+                // every use of =* refers to its own location, so it is never shared
+                let label = next_label(&mut literal_count);
+                let at = format!("{label}{SITE_LABEL_SUFFIX}");
+                sites.push(at.clone());
+                literal_pool.push(Literal {
+                    value: literal.clone(),
+                    data: vec![],
+                    label: label.clone(),
+                    site: Some(at),
+                });
+                label
+            } else {
+                let data = literal_data(literal, options.charset)
+                    .map_err(|e| format!("{}\n\tat {}", e, source))?;
+                get_literal_reference(
+                    &mut literal_pool,
+                    literal.clone(),
+                    data,
+                    options.merge_literals,
+                    &mut literal_count,
+                )
+            };
+            *expr = parse(&reference).unwrap();
+        }
+
+        // mark the use site of =* with the current locctr
+        for site in sites {
+            let value = Box::new(parse("*").unwrap());
+            let frame = Frame::from(
+                FrameInner::Directive(Directive::EQU(EQU { value })),
+                Some(site),
+                &program[i],
+            );
+            program.insert(i, frame);
+            i += 1;
+        }
+
         i += 1;
     }
 
-    program
+    Ok(program)
 }
 
-fn dump_pool(literal_pool: &HashMap<String, String>, ltorg: &Frame) -> Vec<Frame> {
-    let mut frames = Vec::new();
+/// Converts a literal without the leading `=` into its data,
+/// `W'5'` and `5` are words, the others are handled like BYTE constants.
+fn literal_data(literal: &str, charset: Charset) -> Result<Vec<u8>, String> {
+    let word = if let Some(value) = literal.strip_prefix("W'") {
+        Some(
+            value
+                .strip_suffix('\'')
+                .ok_or(format!("Invalid word literal \"{}\"", literal))?,
+        )
+    } else if literal.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+        Some(literal)
+    } else {
+        None
+    };
 
-    for (value, symbol) in literal_pool {
-        let data = literal_to_data(value).unwrap();
-        let frame = Frame::from(
-            FrameInner::Directive(Directive::BYTE(BYTE { data })),
-            Some(symbol.clone()),
-            ltorg,
-        );
-        frames.push(frame);
+    match word {
+        Some(value) => {
+            let (negative, digits) = match value.strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, value),
+            };
+            let value = parse_constant(digits)?;
+            let value = if negative { -value } else { value };
+            if !(-0x800000..=0xFFFFFF).contains(&value) {
+                return Err(format!("Word literal \"{}\" out of range", literal));
+            }
+            Ok(vec![(value >> 16) as u8, (value >> 8) as u8, value as u8])
+        }
+        None => literal_to_data(literal, charset),
     }
-
-    frames
 }
 
-fn get_literal(frame: &Format34) -> Option<String> {
-    match *frame.value {
-        expression::Expression::Literal(ref l) => Some(l.clone()),
-        _ => None,
+fn dump_pool(
+    literal_pool: &mut Vec<Literal>,
+    ltorg: &Frame,
+    options: &AssemblerOptions,
+) -> Vec<Frame> {
+    let mut literals = std::mem::take(literal_pool);
+    match options.literal_placement {
+        LiteralPlacement::FirstUse => {}
+        LiteralPlacement::LargestFirst => literals.sort_by_key(|l| std::cmp::Reverse(l.size())),
+        LiteralPlacement::ByName => literals.sort_by(|a, b| a.value.cmp(&b.value)),
     }
+
+    literals
+        .into_iter()
+        .map(|literal| {
+            let inner = match literal.site {
+                Some(site) => {
+                    let words = vec![parse(&site).unwrap()];
+                    Directive::WORD(WORD { words })
+                }
+                None => Directive::BYTE(BYTE {
+                    data: literal.data,
+                    values: vec![],
+                }),
+            };
+            Frame::from(FrameInner::Directive(inner), Some(literal.label), ltorg)
+        })
+        .collect()
+}
+
+/// The prefix of the labels of literal pool entries, followed by a hexadecimal number.
+pub const LITERAL_LABEL_PREFIX: &str = "_L";
+
+/// Appended to the label of a `=*` literal to label the instruction using it.
+const SITE_LABEL_SUFFIX: &str = "_AT";
+
+/// Whether the label names a literal pool entry, e.g. `_L0001`,
+/// or the instruction using a `=*` literal, e.g. `_L0001_AT`.
+pub fn is_literal_label(label: &str) -> bool {
+    let label = label.strip_suffix(SITE_LABEL_SUFFIX).unwrap_or(label);
+    label
+        .strip_prefix(LITERAL_LABEL_PREFIX)
+        .is_some_and(|n| n.len() >= 4 && n.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn next_label(i: &mut u32) -> String {
+    let label = format!("{LITERAL_LABEL_PREFIX}{i:04X}");
+    *i += 1;
+    label
 }
 
 fn get_literal_reference(
-    literal_pool: &mut HashMap<String, String>,
-    content: String,
+    literal_pool: &mut Vec<Literal>,
+    value: String,
+    data: Vec<u8>,
+    merge: bool,
     i: &mut u32,
 ) -> String {
-    // if the literal is already in the pool, return the reference
-    if let Some(reference) = literal_pool.get(&content) {
-        return reference.clone();
+    // if the literal is already in the pool, return the reference,
+    // literals producing the same bytes share storage if requested
+    let existing = literal_pool.iter().find(|literal| {
+        literal.site.is_none() && (literal.value == value || (merge && literal.data == data))
+    });
+    if let Some(literal) = existing {
+        return literal.label.clone();
     }
 
     // otherwise, add the literal to the pool and return the reference
-    let key = format!("_L{i:04X}");
-    *i += 1;
-    literal_pool.insert(content, key.clone());
-    key
+    let label = next_label(i);
+    literal_pool.push(Literal {
+        value,
+        data,
+        label: label.clone(),
+        site: None,
+    });
+    label
 }
 
 #[cfg(test)]
@@ -100,8 +250,7 @@ mod tests {
             }
         }
 
-        let frames = dump_literals(frames);
-        dbg!(&frames);
+        let frames = dump_literals(frames, &AssemblerOptions::default()).unwrap();
 
         assert_eq!(frames.len(), 10);
         match frames[1].inner {
@@ -123,46 +272,122 @@ mod tests {
             _ => panic!("Expected instruction"),
         }
 
-        if frames[3].label == Some("_L0000".to_string()) {
-            match frames[3].inner {
-                FrameInner::Directive(ref d) => match d {
-                    Directive::BYTE(ref b) => {
-                        assert_eq!(b.data, vec![0xF1]);
-                    }
-                    _ => panic!("Expected BYTE directive"),
-                },
-                _ => panic!("Expected directive"),
-            }
-            match frames[4].inner {
-                FrameInner::Directive(ref d) => match d {
-                    Directive::BYTE(ref b) => {
-                        assert_eq!(b.data, vec![0x45, 0x4F, 0x46]);
-                    }
-                    _ => panic!("Expected BYTE directive"),
-                },
-                _ => panic!("Expected directive"),
+        // the literals are placed in order of first use
+        let pool = frames[3..5]
+            .iter()
+            .map(|frame| match frame.inner {
+                FrameInner::Directive(Directive::BYTE(ref b)) => {
+                    (frame.label.clone().unwrap(), b.data.clone())
+                }
+                _ => panic!("Expected BYTE directive"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pool,
+            vec![
+                ("_L0000".to_string(), vec![0xF1]),
+                ("_L0001".to_string(), vec![0x45, 0x4F, 0x46]),
+            ]
+        );
+    }
+
+    fn pool(source: &str, options: &AssemblerOptions) -> Vec<(String, Vec<u8>)> {
+        let mut frames = Vec::<Frame>::new();
+        for (i, line) in source.lines().enumerate() {
+            let frame = Frame::from_source(line, i as u32 + 1).unwrap();
+            if let Some(frame) = frame {
+                frames.push(frame);
             }
-        } else if frames[3].label == Some("_L0001".to_string()) {
-            match frames[3].inner {
-                FrameInner::Directive(ref d) => match d {
-                    Directive::BYTE(ref b) => {
-                        assert_eq!(b.data, vec![0x45, 0x4F, 0x46]);
-                    }
-                    _ => panic!("Expected BYTE directive"),
-                },
-                _ => panic!("Expected directive"),
+        }
+
+        dump_literals(frames, options)
+            .unwrap()
+            .into_iter()
+            .filter_map(|frame| match frame.inner {
+                FrameInner::Directive(Directive::BYTE(b)) => Some((frame.label.unwrap(), b.data)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn literal_placement() {
+        let source = "PROG\tSTART\t0\n\
+                      \tLDA\t=X'05'\n\
+                      \tLDA\t=C'EOF'\n\
+                      \tLDA\t=X'454F46'\n\
+                      \tLDA\t=X'05'\n\
+                      \tEND\tPROG";
+        let label = |l: &str| l.to_string();
+
+        let options = AssemblerOptions::default();
+        assert_eq!(
+            pool(source, &options),
+            vec![
+                (label("_L0000"), vec![0x05]),
+                (label("_L0001"), vec![0x45, 0x4F, 0x46]),
+                (label("_L0002"), vec![0x45, 0x4F, 0x46]),
+            ]
+        );
+
+        let options = AssemblerOptions {
+            merge_literals: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            pool(source, &options),
+            vec![
+                (label("_L0000"), vec![0x05]),
+                (label("_L0001"), vec![0x45, 0x4F, 0x46]),
+            ]
+        );
+
+        let options = AssemblerOptions {
+            literal_placement: LiteralPlacement::LargestFirst,
+            ..Default::default()
+        };
+        assert_eq!(
+            pool(source, &options),
+            vec![
+                (label("_L0001"), vec![0x45, 0x4F, 0x46]),
+                (label("_L0002"), vec![0x45, 0x4F, 0x46]),
+                (label("_L0000"), vec![0x05]),
+            ]
+        );
+
+        let options = AssemblerOptions {
+            literal_placement: LiteralPlacement::ByName,
+            ..Default::default()
+        };
+        assert_eq!(
+            pool(source, &options),
+            vec![
+                (label("_L0001"), vec![0x45, 0x4F, 0x46]),
+                (label("_L0000"), vec![0x05]),
+                (label("_L0002"), vec![0x45, 0x4F, 0x46]),
+            ]
+        );
+    }
+
+    #[test]
+    fn float_literal() {
+        let source = "PROG\tSTART\t0\n\tLDF\t=F'1'\n\tEND\tPROG";
+        let mut frames = Vec::<Frame>::new();
+        for (i, line) in source.lines().enumerate() {
+            let frame = Frame::from_source(line, i as u32 + 1).unwrap();
+            if let Some(frame) = frame {
+                frames.push(frame);
             }
-            match frames[4].inner {
-                FrameInner::Directive(ref d) => match d {
-                    Directive::BYTE(ref b) => {
-                        assert_eq!(b.data, vec![0xF1]);
-                    }
-                    _ => panic!("Expected BYTE directive"),
-                },
-                _ => panic!("Expected directive"),
+        }
+
+        let frames = dump_literals(frames, &AssemblerOptions::default()).unwrap();
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[2].size(), Some(6));
+        match frames[2].inner {
+            FrameInner::Directive(Directive::BYTE(ref b)) => {
+                assert_eq!(b.data, vec![0x40, 0x18, 0x00, 0x00, 0x00, 0x00]);
             }
-        } else {
-            panic!("Expected literal");
+            _ => panic!("Expected BYTE directive"),
         }
     }
 }
@@ -1,26 +1,36 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+use crate::assembler::AssemblerOptions;
 use crate::frame::directive::*;
 use crate::frame::expression::*;
 use crate::frame::record::*;
 use crate::frame::*;
-
-pub fn resolve_symbols(program: Vec<Frame>) -> Result<Vec<Frame>, String> {
+use crate::frameformer::block::BlockTable;
+use crate::frameformer::literal::is_literal_label;
+
+/// Assigns addresses to the frames and resolves the symbols in their expressions,
+/// returns the program with D and R records and the block table of the program.
+/// The predefined symbols of the options are known before any symbol of the source.
+pub fn resolve_symbols(
+    program: Vec<Frame>,
+    options: &AssemblerOptions,
+) -> Result<(Vec<Frame>, BlockTable), String> {
     let mut program = program;
+    scope_local_labels(&mut program)?;
 
     // build symbol table
     let mut symtab = HashMap::<String, Box<Expression>>::new();
 
     // insert registers
-    let register_a = Expression::Resolved(0);
-    let register_x = Expression::Resolved(1);
-    let register_l = Expression::Resolved(2);
-    let register_b = Expression::Resolved(3);
-    let register_s = Expression::Resolved(4);
-    let register_t = Expression::Resolved(5);
-    let register_f = Expression::Resolved(6);
-    let register_pc = Expression::Resolved(8);
-    let register_sw = Expression::Resolved(9);
+    let register_a = Expression::Resolved(0, ExpressionType::Absolute);
+    let register_x = Expression::Resolved(1, ExpressionType::Absolute);
+    let register_l = Expression::Resolved(2, ExpressionType::Absolute);
+    let register_b = Expression::Resolved(3, ExpressionType::Absolute);
+    let register_s = Expression::Resolved(4, ExpressionType::Absolute);
+    let register_t = Expression::Resolved(5, ExpressionType::Absolute);
+    let register_f = Expression::Resolved(6, ExpressionType::Absolute);
+    let register_pc = Expression::Resolved(8, ExpressionType::Absolute);
+    let register_sw = Expression::Resolved(9, ExpressionType::Absolute);
     symtab.insert("A".to_string(), Box::new(register_a));
     symtab.insert("X".to_string(), Box::new(register_x));
     symtab.insert("L".to_string(), Box::new(register_l));
@@ -31,6 +41,32 @@ pub fn resolve_symbols(program: Vec<Frame>) -> Result<Vec<Frame>, String> {
     symtab.insert("PC".to_string(), Box::new(register_pc));
     symtab.insert("SW".to_string(), Box::new(register_sw));
 
+    // insert predefined symbols, which the source cannot redefine
+    for (name, value) in &options.predefined {
+        let expr = Expression::Resolved(*value, ExpressionType::Absolute);
+        symtab.insert(name.clone(), Box::new(expr));
+    }
+    let redefined = program
+        .iter()
+        .filter_map(|frame| {
+            let label = frame.label.as_ref()?;
+            options
+                .predefined
+                .iter()
+                .any(|(name, _)| name == label)
+                .then(|| {
+                    format!(
+                        "Symbol {} is predefined and cannot be redefined\n\tat {}",
+                        label,
+                        frame.sources()[0]
+                    )
+                })
+        })
+        .collect::<Vec<_>>();
+    if !redefined.is_empty() {
+        return Err(redefined.join("\n"));
+    }
+
     // insert external references
     let extrefs = program
         .iter()
@@ -45,78 +81,74 @@ pub fn resolve_symbols(program: Vec<Frame>) -> Result<Vec<Frame>, String> {
     for frame in extrefs {
         if let FrameInner::Directive(directive::Directive::EXTREF(ref extrefs)) = frame.inner {
             for extref in extrefs.names.clone() {
-                let expr = Expression::Unsolved(UnsolvedExpression {
-                    left: ExpressionOperand::Symbol("<EXTERNAL>".to_string()),
-                    op: None,
-                    right: None,
-                });
+                let expr = Expression::Resolved(
+                    0,
+                    ExpressionType::External(vec![ExternalReference {
+                        symbol: extref.clone(),
+                        negative: false,
+                    }]),
+                );
                 symtab.insert(extref, Box::new(expr));
             }
         }
     }
 
-    // locctr maybe untrustable in some cases
-    let mut start = 0;
-    let mut locctr = Some(0);
-    for frame in &mut program {
-        let size = frame.size();
-        let label = frame.label.clone();
-
-        // resolve ORG expression
-        if let FrameInner::Directive(Directive::ORG(ref mut org)) = frame.inner {
-            if let Expression::Unsolved(ref mut e) = *org.address {
-                if e.left == ExpressionOperand::Locctr {
-                    e.left = ExpressionOperand::Value(locctr.unwrap() as i32);
-                }
-            }
-            org.address = evaluate(org.address.clone(), &mut symtab);
-            org.address.eval_and_update();
-        }
-
-        // set locctr to the address of START or ORG
-        if let FrameInner::Directive(ref d) = frame.inner {
-            if let directive::Directive::START(s) = d {
-                locctr = Some(s.address);
-                start = s.address;
-            }
-            if let directive::Directive::ORG(o) = d {
-                locctr = Some(o.address.eval().unwrap() as u32);
-            }
-        }
-
-        // resolve locctr
-        if let FrameInner::Directive(directive::Directive::EQU(EQU { ref mut value })) = frame.inner
-        {
-            if let Expression::Unsolved(ref mut e) = **value {
-                if e.left == ExpressionOperand::Locctr {
-                    e.left = ExpressionOperand::Value(locctr.unwrap() as i32);
-                }
-            }
+    let start = program
+        .iter()
+        .find_map(|frame| match frame.inner {
+            FrameInner::Directive(Directive::START(ref s)) => Some(s.address),
+            _ => None,
+        })
+        .unwrap_or(0);
+
+    // assign addresses until a fixed point is reached,
+    // forward references in RESB, RESW and EQU are resolved in later passes
+    // the blocks are placed after each other once their lengths are known
+    let mut blocks = BlockTable::from_program(&program);
+    let mut starts = vec![None; blocks.len()];
+    // the labels as (block, offset), their addresses follow once the block starts are known
+    let mut locations = HashMap::new();
+    starts[0] = Some(start);
+
+    let mut progress = (count_resolved(&symtab), 1);
+    let (stuck, lengths) = loop {
+        let (stuck, lengths) =
+            assign_addresses(&mut program, &mut symtab, &mut locations, &blocks, &starts)?;
+        for i in 1..starts.len() {
+            starts[i] = starts[i - 1].zip(lengths[i - 1]).map(|(s, l)| s + l);
         }
+        place_labels(&locations, &starts, &mut symtab);
 
-        // insert label into symbol table
-        if let FrameInner::Directive(directive::Directive::EQU(EQU { ref value })) = frame.inner {
-            symtab.insert(label.unwrap(), value.clone());
-        } else if let Some(label) = label {
-            if let Some(locctr) = locctr {
-                let expr = Expression::Resolved(locctr as i32);
-                symtab.insert(label, Box::new(expr));
-            }
+        let now = (count_resolved(&symtab), starts.iter().flatten().count());
+        if now == progress {
+            break (stuck, lengths);
         }
+        progress = now;
+    };
 
-        // try to resolve expression in the symbol table
-        resolve(&mut symtab);
-
-        // advance locctr
-        if locctr.is_some() {
-            let loc = locctr.unwrap();
-            if let Some(size) = size {
-                locctr = Some(loc + size as u32);
-            } else {
-                locctr = None;
-            }
+    // report every undefined symbol and circular reference at once
+    let mut errors = undefined_symbols(&program, &symtab);
+    errors.extend(circular_references(&program, &symtab));
+    if errors.is_empty() {
+        if let Some(frame) = stuck {
+            let expr = frame
+                .expressions()
+                .unwrap_or_default()
+                .into_iter()
+                .find(|e| matches!(e, Expression::Unsolved(_)))
+                .map(|e| e.to_string())
+                .unwrap_or_default();
+            errors.push(format!(
+                "Cannot resolve \"{}\"\n\tat {}",
+                expr,
+                frame.sources()[0]
+            ));
         }
     }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    blocks.layout(start, &lengths.into_iter().flatten().collect::<Vec<_>>());
 
     #[cfg(debug_assertions)]
     dbg!(&symtab);
@@ -132,7 +164,12 @@ pub fn resolve_symbols(program: Vec<Frame>) -> Result<Vec<Frame>, String> {
             frame.inner
         {
             for name in names.clone() {
-                let value = symtab.get(&name).unwrap().eval().unwrap() as u32 - start;
+                let value = symtab.get(&name).and_then(|e| e.eval()).ok_or(format!(
+                    "Undefined symbol {}\n\tat {}",
+                    name,
+                    frame.sources()[0]
+                ))? as u32
+                    - start;
                 let frame = Frame::from(
                     FrameInner::ObjectRecord(ObjectRecord::Define(DefineRecord { name, value })),
                     None,
@@ -176,35 +213,55 @@ pub fn resolve_symbols(program: Vec<Frame>) -> Result<Vec<Frame>, String> {
     let mut i = 0;
     while i < program.len() {
         let frame = &mut program[i];
+        let source = frame.sources()[0].to_string();
         match &mut frame.inner {
             FrameInner::Instruction(i) => match i {
                 instruction::Instruction::Format2(ref mut i) => {
-                    i.register1 = evaluate(i.register1.clone(), &mut symtab);
-                    i.register2 = evaluate(i.register2.clone(), &mut symtab);
+                    i.register1 = evaluate(i.register1.clone(), &mut symtab)
+                        .map_err(|e| format!("{}\n\tat {}", e, source))?;
+                    i.register2 = evaluate(i.register2.clone(), &mut symtab)
+                        .map_err(|e| format!("{}\n\tat {}", e, source))?;
                 }
                 instruction::Instruction::Format34(i) => {
-                    i.value = evaluate(i.value.clone(), &mut symtab);
+                    i.value = evaluate(i.value.clone(), &mut symtab)
+                        .map_err(|e| format!("{}\n\tat {}", e, source))?;
                 }
                 _ => {}
             },
             FrameInner::Directive(d) => match d {
                 Directive::END(ref mut end) => {
-                    end.first = evaluate(end.first.clone(), &mut symtab);
+                    end.first = evaluate(end.first.clone(), &mut symtab)
+                        .map_err(|e| format!("{}\n\tat {}", e, source))?;
+                }
+                Directive::BYTE(ref mut byte) => {
+                    for (_, v) in byte.values.iter_mut() {
+                        *v = *evaluate(Box::new(v.clone()), &mut symtab)
+                            .map_err(|e| format!("{}\n\tat {}", e, source))?;
+                    }
                 }
                 Directive::WORD(ref mut word) => {
-                    word.word = evaluate(word.word.clone(), &mut symtab);
+                    for w in word.words.iter_mut() {
+                        *w = *evaluate(Box::new(w.clone()), &mut symtab)
+                            .map_err(|e| format!("{}\n\tat {}", e, source))?;
+                    }
                 }
                 Directive::RESB(ref mut resb) => {
-                    resb.bytes = evaluate(resb.bytes.clone(), &mut symtab);
+                    resb.bytes = evaluate(resb.bytes.clone(), &mut symtab)
+                        .map_err(|e| format!("{}\n\tat {}", e, source))?;
                 }
                 Directive::RESW(ref mut resw) => {
-                    resw.words = evaluate(resw.words.clone(), &mut symtab);
+                    resw.words = evaluate(resw.words.clone(), &mut symtab)
+                        .map_err(|e| format!("{}\n\tat {}", e, source))?;
                 }
-                Directive::ORG(ref mut org) => {
-                    org.address = evaluate(org.address.clone(), &mut symtab);
+                Directive::ORG(ORG {
+                    address: Some(ref mut address),
+                }) => {
+                    *address = evaluate(address.clone(), &mut symtab)
+                        .map_err(|e| format!("{}\n\tat {}", e, source))?;
                 }
                 Directive::BASE(ref mut base) => {
-                    base.address = evaluate(base.address.clone(), &mut symtab);
+                    base.address = evaluate(base.address.clone(), &mut symtab)
+                        .map_err(|e| format!("{}\n\tat {}", e, source))?;
                 }
                 _ => {}
             },
@@ -225,72 +282,398 @@ pub fn resolve_symbols(program: Vec<Frame>) -> Result<Vec<Frame>, String> {
     // #[cfg(debug_assertions)]
     // dbg!(&program);
 
-    Ok(program)
+    Ok((program, blocks))
 }
 
-fn resolve(symtab: &mut HashMap<String, Box<Expression>>) {
-    let mut updated = true;
-    while updated {
-        updated = false;
-        let cloned = symtab.clone();
-        for (_key, value) in symtab.iter_mut() {
-            if let Expression::Unsolved(ref mut expr) = **value {
-                if let ExpressionOperand::Symbol(ref symbol) = expr.left {
-                    if let Some(left) = cloned.get(symbol) {
-                        if let Expression::Resolved(left) = **left {
-                            expr.left = ExpressionOperand::Value(left);
-                            updated = true;
+/// Walks through the program once, assigning addresses to labels and resolving
+/// expressions with the symbols known so far.
+/// Each block has its own locctr, an offset from the start of the block,
+/// so the addresses in a block are only known once the blocks before it are measured.
+/// Returns the first frame after which the locctr is unknown, if any,
+/// and the lengths of the blocks measured in this pass.
+fn assign_addresses(
+    program: &mut [Frame],
+    symtab: &mut HashMap<String, Box<Expression>>,
+    locations: &mut HashMap<String, (usize, u32)>,
+    blocks: &BlockTable,
+    starts: &[Option<u32>],
+) -> Result<(Option<Frame>, Vec<Option<u32>>), String> {
+    let defined = program
+        .iter()
+        .filter_map(|frame| frame.label.clone())
+        .collect::<HashSet<_>>();
+
+    let mut stuck = None;
+    let mut start = 0;
+    let mut block = 0;
+    let mut offsets = vec![Some(0); blocks.len()];
+    let mut lengths = vec![Some(0); blocks.len()];
+    let mut org_stack = vec![];
+    let mut seen = HashSet::new();
+    for frame in program.iter_mut() {
+        let label = frame.label.clone();
+        let source = frame.sources()[0].to_string();
+        let locctr = starts[block].zip(offsets[block]).map(|(s, o)| s + o);
+
+        // ORG can only move the locctr within the current block
+        if let FrameInner::Directive(Directive::ORG(ORG {
+            address: Some(ref address),
+        })) = frame.inner
+        {
+            let other = address.deps().into_iter().find_map(|d| {
+                locations
+                    .get(d)
+                    .filter(|(b, _)| *b != block)
+                    .map(|l| (d, l.0))
+            });
+            if let Some((symbol, other)) = other {
+                return Err(format!(
+                    "ORG operand {} is in block {}, not in the current block {}\n\tat {}",
+                    symbol,
+                    blocks.get(other).unwrap().display_name(),
+                    blocks.get(block).unwrap().display_name(),
+                    source
+                ));
+            }
+        }
+
+        // resolve locctr and known symbols in expressions
+        for expr in frame.expressions_mut().unwrap_or_default() {
+            if let Some(locctr) = locctr {
+                expr.resolve_locctr(locctr as i32);
+            }
+            *expr = *evaluate(Box::new(expr.clone()), symtab)
+                .map_err(|e| format!("{}\n\tat {}", e, source))?;
+        }
+
+        // switch blocks, set locctr to the address of START or ORG
+        if let FrameInner::Directive(ref d) = frame.inner {
+            if let directive::Directive::START(s) = d {
+                start = s.address;
+            }
+            if let directive::Directive::USE(u) = d {
+                block = blocks.number(&u.name).unwrap();
+            }
+            if let directive::Directive::ORG(o) = d {
+                match o.address {
+                    Some(ref address) => {
+                        // symbols in the operand must be defined before ORG,
+                        // those defined earlier may still be resolved in a later pass
+                        let forward = address
+                            .deps()
+                            .into_iter()
+                            .find(|d| defined.contains(*d) && !seen.contains(*d));
+                        if let Some(symbol) = forward {
+                            return Err(format!(
+                                "Forward reference to {} in ORG operand\n\tat {}",
+                                symbol, source
+                            ));
+                        }
+                        if let Some(ExpressionType::External(_)) = address.kind() {
+                            return Err(format!(
+                                "ORG operand cannot refer to external symbols\n\tat {}",
+                                source
+                            ));
+                        }
+                        if let Some(address) = address.eval() {
+                            if address < start as i32 {
+                                return Err(format!(
+                                    "ORG moves the location counter below the section start {:04X}\n\tat {}",
+                                    start, source
+                                ));
+                            }
                         }
-                    }
-                }
 
-                if let Some(ExpressionOperand::Symbol(ref symbol)) = expr.right {
-                    if let Some(right) = cloned.get(symbol) {
-                        if let Expression::Resolved(right) = **right {
-                            expr.right = Some(ExpressionOperand::Value(right));
-                            updated = true;
+                        org_stack.push((block, offsets[block]));
+                        offsets[block] = match address.eval().zip(starts[block]) {
+                            Some((address, start)) => {
+                                Some((address as u32).checked_sub(start).ok_or(format!(
+                                    "ORG moves the location counter before block {}\n\tat {}",
+                                    blocks.get(block).unwrap().display_name(),
+                                    source
+                                ))?)
+                            }
+                            None => None,
+                        };
+                    }
+                    None => {
+                        let (saved, offset) = org_stack.pop().ok_or(format!(
+                            "No location counter to restore by ORG\n\tat {}",
+                            source
+                        ))?;
+                        // the saved location counter belongs to the block of the ORG it undoes
+                        if saved != block {
+                            return Err(format!(
+                                "ORG cannot restore the location counter of block {} in block {}\n\tat {}",
+                                blocks.get(saved).unwrap().display_name(),
+                                blocks.get(block).unwrap().display_name(),
+                                source
+                            ));
                         }
+                        offsets[block] = offset;
                     }
                 }
+                if offsets[block].is_none() && stuck.is_none() {
+                    stuck = Some(frame.clone());
+                }
             }
+        }
+
+        // insert label into symbol table
+        seen.extend(label.clone());
+        if let FrameInner::Directive(directive::Directive::EQU(EQU { ref value })) = frame.inner {
+            symtab.insert(label.clone().unwrap(), value.clone());
+        } else if let Some(ref label) = label {
+            if let Some(offset) = offsets[block] {
+                locations.insert(label.clone(), (block, offset));
+                if let Some(start) = starts[block] {
+                    let expr =
+                        Expression::Resolved((start + offset) as i32, ExpressionType::Relative);
+                    symtab.insert(label.clone(), Box::new(expr));
+                }
+            }
+        }
+
+        // try to resolve expression in the symbol table
+        resolve(symtab).map_err(|e| format!("{}\n\tat {}", e, source))?;
 
-            value.eval_and_update();
+        // keep the resolved value for the next pass
+        if let FrameInner::Directive(directive::Directive::EQU(EQU { ref mut value })) = frame.inner
+        {
+            *value = symtab[&label.unwrap()].clone();
+        }
+
+        // advance locctr, the length of a block is the highest locctr reached
+        if let Some(offset) = offsets[block] {
+            match frame.size() {
+                Some(size) => {
+                    let offset = offset + size as u32;
+                    offsets[block] = Some(offset);
+                    lengths[block] = lengths[block].map(|length| length.max(offset));
+                }
+                None => {
+                    offsets[block] = None;
+                    lengths[block] = None;
+                    if stuck.is_none() {
+                        stuck = Some(frame.clone());
+                    }
+                }
+            }
         }
     }
+
+    Ok((stuck, lengths))
 }
 
-fn evaluate(
-    mut expr: Box<Expression>,
+/// Gives the labels of the blocks whose start is known their addresses.
+fn place_labels(
+    locations: &HashMap<String, (usize, u32)>,
+    starts: &[Option<u32>],
     symtab: &mut HashMap<String, Box<Expression>>,
-) -> Box<Expression> {
-    if let Expression::Unsolved(ref mut expr) = *expr {
-        if let ExpressionOperand::Symbol(ref symbol) = expr.left {
-            if let Some(value) = symtab.get(symbol) {
-                if let Expression::Resolved(value) = **value {
-                    expr.left = ExpressionOperand::Value(value);
+) {
+    for (label, (block, offset)) in locations {
+        if let Some(start) = starts[*block] {
+            let expr = Expression::Resolved((start + offset) as i32, ExpressionType::Relative);
+            symtab.insert(label.clone(), Box::new(expr));
+        }
+    }
+}
+
+/// Renames the local labels, e.g. `?LOOP`, after the global label they follow,
+/// so that each routine can have its own `?LOOP`, e.g. `READ?LOOP` and `WRITE?LOOP`.
+/// The labels of `EQU` other than `EQU *`, of macro expansions (`$`) and of literal
+/// pools do not start a new routine. Such names cannot be written
+/// in the source, so local labels are only visible in their routine and cannot
+/// be exported by EXTDEF.
+fn scope_local_labels(program: &mut [Frame]) -> Result<(), String> {
+    let mut errors = vec![];
+    let mut scope = String::new();
+    for frame in program.iter_mut() {
+        let source = frame.sources()[0].to_string();
+        match (&frame.inner, &frame.label) {
+            (FrameInner::Directive(Directive::EXTDEF(extdef)), _) => {
+                for name in extdef.names.iter().filter(|name| name.starts_with('?')) {
+                    errors.push(format!(
+                        "Local label {} cannot be exported\n\tat {}",
+                        name, source
+                    ));
+                }
+            }
+            (_, Some(label)) if label.starts_with('?') => {
+                let valid = label.len() > 1
+                    && label[1..]
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+                if !valid {
+                    errors.push(format!("Invalid local label {}\n\tat {}", label, source));
                 }
             }
+            // labels generated for macro expansions and literal pools belong to the routine
+            (_, Some(label)) if label.starts_with('$') || is_literal_label(label) => {}
+            // `EQU *` labels a location like the label of a macro call does
+            (FrameInner::Directive(Directive::EQU(equ)), _)
+                if !matches!(
+                    *equ.value,
+                    Expression::Unsolved(UnsolvedExpression::Operand(ExpressionOperand::Locctr))
+                ) => {}
+            (_, Some(label)) => scope = label.clone(),
+            _ => {}
         }
 
-        if let Some(ref mut right) = expr.right {
-            if let ExpressionOperand::Symbol(ref symbol) = right {
-                if let Some(value) = symtab.get(symbol) {
-                    if let Expression::Resolved(value) = **value {
-                        *right = ExpressionOperand::Value(value);
-                    }
-                }
+        if let Some(label) = frame.label.as_mut().filter(|label| label.starts_with('?')) {
+            *label = format!("{}{}", scope, label);
+        }
+        for expr in frame.expressions_mut().unwrap_or_default() {
+            expr.rename(|symbol| {
+                symbol
+                    .starts_with('?')
+                    .then(|| format!("{}{}", scope, symbol))
+            });
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(())
+}
+
+/// Reports the symbols used in expressions or EXTDEF but never defined.
+fn undefined_symbols(program: &[Frame], symtab: &HashMap<String, Box<Expression>>) -> Vec<String> {
+    let mut errors = vec![];
+    for frame in program {
+        let mut names = frame
+            .expressions()
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|e| e.deps())
+            .collect::<Vec<_>>();
+        if let FrameInner::Directive(Directive::EXTDEF(EXTDEF { names: ref defs })) = frame.inner {
+            names.extend(defs.iter().map(|name| name.as_str()));
+        }
+
+        let mut reported = HashSet::new();
+        for name in names {
+            if !symtab.contains_key(name) && reported.insert(name) {
+                errors.push(format!(
+                    "Undefined symbol {}\n\tat {}",
+                    name,
+                    frame.sources()[0]
+                ));
+            }
+        }
+    }
+    errors
+}
+
+/// Reports the cycles of EQU symbols depending on each other, e.g. `A EQU B` and `B EQU A`.
+fn circular_references(
+    program: &[Frame],
+    symtab: &HashMap<String, Box<Expression>>,
+) -> Vec<String> {
+    // the unresolved EQU symbols in order of definition
+    let definitions = program
+        .iter()
+        .filter_map(|frame| match frame.inner {
+            FrameInner::Directive(Directive::EQU(_)) => {
+                let label = frame.label.clone()?;
+                matches!(
+                    symtab.get(&label).map(|e| &**e),
+                    Some(Expression::Unsolved(_))
+                )
+                .then(|| (label, frame.sources()[0].to_string()))
             }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let sources = definitions.iter().cloned().collect::<HashMap<_, _>>();
+
+    let mut errors = vec![];
+    let mut visited = HashSet::new();
+    for (symbol, _) in &definitions {
+        let mut path = vec![];
+        find_cycles(
+            symbol,
+            symtab,
+            &sources,
+            &mut visited,
+            &mut path,
+            &mut errors,
+        );
+    }
+    errors
+}
+
+/// Depth-first search through the dependencies of a symbol,
+/// a dependency already on the path closes a cycle.
+fn find_cycles(
+    symbol: &str,
+    symtab: &HashMap<String, Box<Expression>>,
+    sources: &HashMap<String, String>,
+    visited: &mut HashSet<String>,
+    path: &mut Vec<String>,
+    errors: &mut Vec<String>,
+) {
+    if let Some(index) = path.iter().position(|s| s == symbol) {
+        let cycle = &path[index..];
+        let mut error = format!("Circular reference {} -> {}", cycle.join(" -> "), symbol);
+        for symbol in cycle {
+            error.push_str(&format!("\n\tat {}", sources[symbol]));
         }
+        errors.push(error);
+        return;
+    }
+    if !sources.contains_key(symbol) || !visited.insert(symbol.to_string()) {
+        return;
     }
-    expr.eval_and_update();
-    expr
+
+    path.push(symbol.to_string());
+    for dep in symtab[symbol].deps() {
+        find_cycles(dep, symtab, sources, visited, path, errors);
+    }
+    path.pop();
+}
+
+fn count_resolved(symtab: &HashMap<String, Box<Expression>>) -> usize {
+    symtab
+        .values()
+        .filter(|e| matches!(***e, Expression::Resolved(..)))
+        .count()
+}
+
+fn resolve(symtab: &mut HashMap<String, Box<Expression>>) -> Result<(), String> {
+    let mut updated = true;
+    while updated {
+        updated = false;
+        let cloned = symtab.clone();
+        for (_key, value) in symtab.iter_mut() {
+            updated |= value.substitute(|symbol| match cloned.get(symbol).map(|e| &**e) {
+                Some(Expression::Resolved(value, kind)) => Some((*value, kind.clone())),
+                _ => None,
+            });
+
+            value.eval_and_update()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn evaluate(
+    mut expr: Box<Expression>,
+    symtab: &mut HashMap<String, Box<Expression>>,
+) -> Result<Box<Expression>, String> {
+    expr.substitute(|symbol| match symtab.get(symbol).map(|e| &**e) {
+        Some(Expression::Resolved(value, kind)) => Some((*value, kind.clone())),
+        _ => None,
+    });
+    expr.eval_and_update()?;
+    Ok(expr)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::frameformer::{
-        block::rearrange_blocks, literal::dump_literals, section::split_into_sections,
-    };
+    use crate::frameformer::{literal::dump_literals, section::split_into_sections};
 
     use super::*;
     use std::fs;
@@ -308,9 +691,8 @@ mod tests {
 
         let programs = split_into_sections(frames);
         let first = programs[0].clone();
-        let frames = rearrange_blocks(first);
-        let frames = dump_literals(frames);
-        let frames = resolve_symbols(frames).unwrap();
+        let frames = dump_literals(first, &AssemblerOptions::default()).unwrap();
+        let (frames, _) = resolve_symbols(frames, &AssemblerOptions::default()).unwrap();
         dbg!(&frames);
 
         assert_eq!(frames.len(), 24);
@@ -329,9 +711,8 @@ mod tests {
 
         let programs = split_into_sections(frames);
         let first = programs[0].clone();
-        let frames = rearrange_blocks(first);
-        let frames = dump_literals(frames);
-        let frames = resolve_symbols(frames).unwrap();
+        let frames = dump_literals(first, &AssemblerOptions::default()).unwrap();
+        let (frames, _) = resolve_symbols(frames, &AssemblerOptions::default()).unwrap();
         dbg!(&frames);
 
         assert_eq!(frames.len(), 46);
@@ -1,10 +1,18 @@
+use crate::assembler::AssemblerOptions;
 use crate::frame::{
-    expression::{Expression, ExpressionOperand},
+    expression::{Expression, ExpressionType},
+    instruction::{check_number, SHIFT_COUNTS, SVC_NUMBERS},
+    optab::OperandSignature,
     record::{EndRecord, HeaderRecord, ModificationRecord, ObjectRecord, TextRecord},
     *,
 };
+use crate::frameformer::block::BlockTable;
 
-pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, String> {
+pub fn translate_to_record(
+    program: Vec<Frame>,
+    blocks: &BlockTable,
+    options: &AssemblerOptions,
+) -> Result<Vec<ObjectRecord>, String> {
     let r_records = program
         .iter()
         .filter(|frame| {
@@ -36,9 +44,13 @@ pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, Str
     let mut h_record: Option<Frame> = None;
     let mut e_record: Option<Frame> = None;
 
-    let mut start = 0;
-    let mut locctr = Some(0);
+    // each block has its own locctr, starting at the address of the block
+    let mut locctrs = blocks.iter().map(|b| b.start).collect::<Vec<_>>();
+    let mut block = 0;
+    let mut segment = 0;
+    let mut locctr = Some(locctrs[0]);
     let mut base: Option<u32> = None;
+    let mut org_stack = vec![];
     for frame in &mut program {
         let size = frame.size();
         // set locctr to the address of START or ORG
@@ -46,13 +58,29 @@ pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, Str
             match d {
                 directive::Directive::START(s) => {
                     locctr = Some(s.address);
-                    start = s.address;
                 }
-                directive::Directive::ORG(o) => {
-                    locctr = Some(o.address.eval().unwrap() as u32);
+                directive::Directive::USE(u) => {
+                    locctrs[block] = locctr.unwrap();
+                    block = blocks.number(&u.name).unwrap();
+                    locctr = Some(locctrs[block]);
+                    segment += 1;
                 }
+                directive::Directive::ORG(o) => match o.address {
+                    Some(ref address) => {
+                        org_stack.push((block, locctr));
+                        locctr = Some(resolved(address, frame)?.0 as u32);
+                    }
+                    None => {
+                        // restores across USE are rejected by the symbol resolver
+                        if let Some((saved, saved_locctr)) = org_stack.pop() {
+                            if saved == block {
+                                locctr = saved_locctr;
+                            }
+                        }
+                    }
+                },
                 directive::Directive::BASE(b) => {
-                    base = Some(b.address.eval().unwrap() as u32);
+                    base = Some(resolved(&b.address, frame)?.0 as u32);
                 }
                 directive::Directive::NOBASE(_) => {
                     base = None;
@@ -69,6 +97,7 @@ pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, Str
                         t_records.push(Frame::from(
                             FrameInner::ObjectRecord(ObjectRecord::Text(TextRecord {
                                 start: locctr.unwrap(),
+                                segment,
                                 length: 1,
                                 data: vec![i.opcode],
                             })),
@@ -77,12 +106,31 @@ pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, Str
                         ));
                     }
                     instruction::Instruction::Format2(i) => {
-                        let r1 = i.register1.eval().unwrap() as u8;
-                        let r2 = i.register2.eval().unwrap() as u8;
+                        let (r1, kind1) = resolved(&i.register1, frame)?;
+                        let (r2, kind2) = resolved(&i.register2, frame)?;
+                        // SVC numbers and shift counts given by symbols are checked once resolved
+                        let checked = match i.operands {
+                            OperandSignature::Number => Some((kind1, r1, SVC_NUMBERS)),
+                            OperandSignature::RegisterCount => Some((kind2, r2 + 1, SHIFT_COUNTS)),
+                            _ => None,
+                        };
+                        if let Some((kind, value, range)) = checked {
+                            if kind != ExpressionType::Absolute {
+                                return Err(format!(
+                                    "Number {} must be absolute\n\tat {}",
+                                    value,
+                                    frame.sources()[0]
+                                ));
+                            }
+                            check_number(value, &range)
+                                .map_err(|e| format!("{}\n\tat {}", e, frame.sources()[0]))?;
+                        }
+                        let (r1, r2) = (r1 as u8, r2 as u8);
                         let operand = r1 << 4 | r2;
                         t_records.push(Frame::from(
                             FrameInner::ObjectRecord(ObjectRecord::Text(TextRecord {
                                 start: locctr.unwrap(),
+                                segment,
                                 length: 2,
                                 data: vec![i.opcode, operand],
                             })),
@@ -91,208 +139,91 @@ pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, Str
                         ));
                     }
                     instruction::Instruction::Format34(i) => {
-                        // RSUB
-                        if i.opcode == 0x4C {
+                        // operation without operand, e.g. RSUB
+                        if i.operands == OperandSignature::None {
                             t_records.push(Frame::from(
                                 FrameInner::ObjectRecord(ObjectRecord::Text(TextRecord {
                                     start: locctr.unwrap(),
+                                    segment,
                                     length: 3,
-                                    data: vec![0x4C | 0b11, 0, 0],
+                                    data: vec![i.opcode | 0b11, 0, 0],
                                 })),
                                 None,
                                 frame,
                             ));
                         } else {
-                            // IT'A A MESS!! I DON'T KNOW WHAT I'M DOING!!
-                            let mut data = vec![];
                             let length = i.is_format4() as u32 + 3;
-                            let operand = i.value.eval();
-                            let (operand, external) = match operand {
-                                Some(v) => (v, false),
-                                _ => {
-                                    // external reference involved
-                                    // create modification record
-                                    let mut expr = *i.value.clone();
-                                    if let Expression::Unsolved(ref mut u) = expr {
-                                        if let ExpressionOperand::Symbol(s) = &u.left {
-                                            m_records.push(Frame::from(
-                                                FrameInner::ObjectRecord(
-                                                    ObjectRecord::Modification(
-                                                        ModificationRecord {
-                                                            start: locctr.unwrap() + 1,
-                                                            length: if i.is_format4() {
-                                                                5
-                                                            } else {
-                                                                3
-                                                            },
-                                                            symbol: format!("+{}", s),
-                                                        },
-                                                    ),
-                                                ),
-                                                None,
-                                                frame,
-                                            ));
-                                            u.left = ExpressionOperand::Value(0);
-                                        }
-
-                                        let op = &u.op;
+                            let (operand, kind) = resolved(&i.value, frame)?;
 
-                                        if let Some(ExpressionOperand::Symbol(s)) = &u.right {
-                                            m_records.push(Frame::from(
-                                                FrameInner::ObjectRecord(
-                                                    ObjectRecord::Modification(
-                                                        ModificationRecord {
-                                                            start: locctr.unwrap() + 1,
-                                                            length: if i.is_format4() {
-                                                                5
-                                                            } else {
-                                                                3
-                                                            },
-                                                            symbol: format!(
-                                                                "{}{}",
-                                                                op.clone().unwrap(),
-                                                                s
-                                                            ),
-                                                        },
-                                                    ),
-                                                ),
-                                                None,
-                                                frame,
-                                            ));
-                                            let val = match op.clone().unwrap() {
-                                                expression::ExpressionOperator::Add
-                                                | expression::ExpressionOperator::Subtract => 0,
-                                                expression::ExpressionOperator::Multiply
-                                                | expression::ExpressionOperator::Divide => 1,
-                                            };
-                                            u.right = Some(ExpressionOperand::Value(val));
-                                        }
-                                    }
-
-                                    (expr.eval().unwrap(), true)
-                                }
-                            };
-
-                            let source = &frame.sources()[0];
-                            let is_number = if let FrameSource::Source(src, _) = source {
-                                // find the next character of '#' is a number, then it is a number
-                                let mut chars = src.chars();
-                                let mut is_number = false;
-                                while let Some(c) = chars.next() {
-                                    if c == '#' {
-                                        if let Some(c) = chars.next() {
-                                            is_number = c.is_numeric();
-                                        }
-                                        break;
-                                    }
-                                }
-                                is_number
+                            // simple addressing sets both n and i
+                            let nixbpe = if i.nixbpe & 0b110000 == 0 {
+                                i.nixbpe | 0b110000
                             } else {
-                                false
+                                i.nixbpe
                             };
 
                             let pc = locctr.unwrap() + length;
-                            if i.is_format4() {
-                                let nixbpe = if is_number {
-                                    i.nixbpe
-                                } else {
-                                    i.nixbpe | 0b110000
-                                };
-                                data.push(i.opcode | (nixbpe & 0b110000) >> 4);
-                                data.push(nixbpe << 4 | (operand >> 16) as u8);
-                                data.push((operand >> 8) as u8);
-                                data.push(operand as u8);
-
-                                if start == 0 && (i.nixbpe & 0b010000) == 0 && !external {
-                                    m_records.push(Frame::from(
-                                        FrameInner::ObjectRecord(ObjectRecord::Modification(
-                                            ModificationRecord {
-                                                start: locctr.unwrap() + 1,
-                                                length: 5,
-                                                symbol: String::new(),
-                                            },
-                                        )),
-                                        frame.label.clone(),
-                                        frame,
-                                    ));
-                                }
-                            } else if i.is_immediate() {
-                                if is_number {
-                                    let nixbpe = i.nixbpe;
-                                    let disp = operand;
-                                    data.push(i.opcode | (nixbpe & 0b110000) >> 4);
-                                    data.push(nixbpe << 4 | ((disp & 0b111100000000) >> 8) as u8);
-                                    data.push(disp as u8);
-                                } else {
-                                    let nixbpe = i.nixbpe | 0b000010;
-                                    let disp = operand - pc as i32;
-                                    data.push(i.opcode | (nixbpe & 0b110000) >> 4);
-                                    data.push(nixbpe << 4 | ((disp & 0b111100000000) >> 8) as u8);
-                                    data.push(disp as u8);
-                                }
-                            // } else if let Some(base) = base {
-                            //     let disp = operand as i32 - base as i32;
-                            //     if disp >= 0 && disp <= 4095 {
-                            //         let nixbpe = i.nixbpe | 0b110100;
-                            //         data.push(i.opcode | (nixbpe & 0b110000) >> 4);
-                            //         data.push(nixbpe << 4 | ((disp & 0b111100000000) >> 8) as u8);
-                            //         data.push(disp as u8);
-                            //     } else {
-                            //         // calculate displacement
-                            //         let mut reachable = false;
-                            //         // try PC relative first
-                            //         let disp = operand as i32 - pc as i32;
-                            //         if disp >= -2048 && disp <= 2047 {
-                            //             let nixbpe = i.nixbpe | 0b110010;
-                            //             data.push(i.opcode | (nixbpe & 0b110000) >> 4);
-                            //             data.push(nixbpe << 4 | ((disp & 0b111100000000) >> 8) as u8);
-                            //             data.push(disp as u8);
-                            //             reachable = true;
-                            //         }
+                            let data = if i.is_format4() {
+                                relocate(
+                                    &kind,
+                                    locctr.unwrap() + 1,
+                                    5,
+                                    options,
+                                    frame,
+                                    &mut m_records,
+                                );
 
-                            //         if !reachable {
-                            //             return Err(format!("Operand out of range: {}", i.value));
-                            //         }
-                            //     }
-                            } else if i.is_indirect() {
-                                let nixbpe = i.nixbpe | 0b000010;
-                                let disp = operand - pc as i32;
-                                data.push(i.opcode | (nixbpe & 0b110000) >> 4);
-                                data.push(nixbpe << 4 | ((disp & 0b111100000000) >> 8) as u8);
-                                data.push(disp as u8);
+                                vec![
+                                    i.opcode | (nixbpe & 0b110000) >> 4,
+                                    nixbpe << 4 | (operand >> 16 & 0xF) as u8,
+                                    (operand >> 8) as u8,
+                                    operand as u8,
+                                ]
                             } else {
-                                // calculate displacement
-                                let mut reachable = false;
-                                // try PC relative first
-                                let disp = operand - pc as i32;
-                                if (-2048..=2047).contains(&disp) {
-                                    let nixbpe = i.nixbpe | 0b110010;
-                                    data.push(i.opcode | (nixbpe & 0b110000) >> 4);
-                                    data.push(nixbpe << 4 | ((disp & 0b111100000000) >> 8) as u8);
-                                    data.push(disp as u8);
-                                    reachable = true;
-                                } else if let Some(base) = base {
-                                    // try BASE relative
-                                    let disp = operand - base as i32;
-                                    if (0..=4095).contains(&disp) {
-                                        let nixbpe = i.nixbpe | 0b110100;
-                                        data.push(i.opcode | (nixbpe & 0b110000) >> 4);
-                                        data.push(
-                                            nixbpe << 4 | ((disp & 0b111100000000) >> 8) as u8,
-                                        );
-                                        data.push(disp as u8);
-                                        reachable = true;
+                                match kind {
+                                    ExpressionType::Absolute => {
+                                        // direct addressing, e.g. a constant
+                                        if !(0..=4095).contains(&operand) {
+                                            return Err(format!(
+                                                "Operand out of range: {}\n\tat {}",
+                                                i.value,
+                                                frame.sources()[0]
+                                            ));
+                                        }
+                                        format3(i.opcode, nixbpe, operand)
+                                    }
+                                    ExpressionType::Relative => {
+                                        // try PC relative first, then BASE relative
+                                        let disp = operand - pc as i32;
+                                        let base_disp = base.map(|base| operand - base as i32);
+                                        if (-2048..=2047).contains(&disp) {
+                                            format3(i.opcode, nixbpe | 0b000010, disp)
+                                        } else if let Some(disp) =
+                                            base_disp.filter(|disp| (0..=4095).contains(disp))
+                                        {
+                                            format3(i.opcode, nixbpe | 0b000100, disp)
+                                        } else {
+                                            return Err(format!(
+                                                "Operand out of range: {}\n\tat {}",
+                                                i.value,
+                                                frame.sources()[0]
+                                            ));
+                                        }
+                                    }
+                                    ExpressionType::External(_) => {
+                                        return Err(format!(
+                                            "External reference requires format 4: {}\n\tat {}",
+                                            i.value,
+                                            frame.sources()[0]
+                                        ));
                                     }
                                 }
-
-                                if !reachable {
-                                    return Err(format!("Operand out of range: {}", i.value));
-                                }
-                            }
+                            };
 
                             t_records.push(Frame::from(
                                 FrameInner::ObjectRecord(ObjectRecord::Text(TextRecord {
                                     start: locctr.unwrap(),
+                                    segment,
                                     length,
                                     data,
                                 })),
@@ -305,11 +236,29 @@ pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, Str
             }
             FrameInner::Directive(ref d) => match d {
                 directive::Directive::BYTE(b) => {
+                    let mut data = b.data.clone();
+                    for (offset, expr) in &b.values {
+                        let (value, kind) = resolved(expr, frame)?;
+                        data[*offset] = directive::byte_value(value)
+                            .map_err(|e| format!("{}\n\tat {}", e, frame.sources()[0]))?;
+                        // a single byte cannot hold a relocated address, only external
+                        // references are left to the loader
+                        if kind == ExpressionType::Relative && !options.absolute {
+                            return Err(format!(
+                                "Byte value {} is a relative address and cannot be relocated\n\tat {}",
+                                expr,
+                                frame.sources()[0]
+                            ));
+                        }
+                        let start = locctr.unwrap() + *offset as u32;
+                        relocate(&kind, start, 2, options, frame, &mut m_records);
+                    }
                     t_records.push(Frame::from(
                         FrameInner::ObjectRecord(ObjectRecord::Text(TextRecord {
                             start: locctr.unwrap(),
-                            length: b.data.len() as u32,
-                            data: b.data.clone(),
+                            segment,
+                            length: data.len() as u32,
+                            data,
                         })),
                         None,
                         frame,
@@ -317,64 +266,20 @@ pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, Str
                 }
                 directive::Directive::WORD(w) => {
                     let mut data = vec![];
-                    let value = w.word.eval();
-                    let mut value = match value {
-                        Some(v) => v,
-                        _ => {
-                            // external reference involved
-                            // create modification record
-                            let mut expr = *w.word.clone();
-                            if let Expression::Unsolved(ref mut u) = expr {
-                                if let ExpressionOperand::Symbol(s) = &u.left {
-                                    m_records.push(Frame::from(
-                                        FrameInner::ObjectRecord(ObjectRecord::Modification(
-                                            ModificationRecord {
-                                                start: locctr.unwrap() + 1,
-                                                length: 6,
-                                                symbol: format!("+{}", s),
-                                            },
-                                        )),
-                                        None,
-                                        frame,
-                                    ));
-                                    u.left = ExpressionOperand::Value(0);
-                                }
-
-                                let op = &u.op;
-
-                                if let Some(ExpressionOperand::Symbol(s)) = &u.right {
-                                    m_records.push(Frame::from(
-                                        FrameInner::ObjectRecord(ObjectRecord::Modification(
-                                            ModificationRecord {
-                                                start: locctr.unwrap() + 1,
-                                                length: 6,
-                                                symbol: format!("{}{}", op.clone().unwrap(), s),
-                                            },
-                                        )),
-                                        None,
-                                        frame,
-                                    ));
-                                    let val = match op.clone().unwrap() {
-                                        expression::ExpressionOperator::Add
-                                        | expression::ExpressionOperator::Subtract => 0,
-                                        expression::ExpressionOperator::Multiply
-                                        | expression::ExpressionOperator::Divide => 1,
-                                    };
-                                    u.right = Some(ExpressionOperand::Value(val));
-                                }
-                            }
-
-                            expr.eval().unwrap()
+                    for word in &w.words {
+                        // each word is relocated on its own
+                        let (value, kind) = resolved(word, frame)?;
+                        let start = locctr.unwrap() + data.len() as u32;
+                        relocate(&kind, start, 6, options, frame, &mut m_records);
+                        for shift in [16, 8, 0] {
+                            data.push((value >> shift) as u8);
                         }
-                    };
-                    for _ in 0..3 {
-                        data.push((value & 0xFF) as u8);
-                        value >>= 8;
                     }
                     t_records.push(Frame::from(
                         FrameInner::ObjectRecord(ObjectRecord::Text(TextRecord {
                             start: locctr.unwrap(),
-                            length: 3,
+                            segment,
+                            length: data.len() as u32,
                             data,
                         })),
                         None,
@@ -395,7 +300,7 @@ pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, Str
                 directive::Directive::END(e) => {
                     e_record = Some(Frame::from(
                         FrameInner::ObjectRecord(ObjectRecord::End(EndRecord {
-                            start: e.first.eval().unwrap() as u32,
+                            start: resolved(&e.first, frame)?.0 as u32,
                         })),
                         None,
                         frame,
@@ -419,7 +324,7 @@ pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, Str
 
     if let Some(h) = h_record.as_mut() {
         if let FrameInner::ObjectRecord(ObjectRecord::Header(ref mut h)) = h.inner {
-            h.length = locctr.unwrap() - start;
+            h.length = blocks.length();
         }
     }
 
@@ -454,11 +359,63 @@ pub fn translate_to_record(program: Vec<Frame>) -> Result<Vec<ObjectRecord>, Str
         .collect())
 }
 
+/// Returns the value and the type of a resolved expression.
+fn resolved(expr: &Expression, frame: &Frame) -> Result<(i32, ExpressionType), String> {
+    match expr.resolve() {
+        Ok(Some(resolved)) => Ok(resolved),
+        Ok(None) => Err(format!(
+            "Cannot evaluate expression {}\n\tat {}",
+            expr,
+            frame.sources()[0]
+        )),
+        Err(e) => Err(format!("{}\n\tat {}", e, frame.sources()[0])),
+    }
+}
+
+/// Creates the modification records of a field containing a value of the given type,
+/// relative values are relocated by the program start unless the program is absolute,
+/// external references are added or subtracted by the loader.
+fn relocate(
+    kind: &ExpressionType,
+    start: u32,
+    length: u32,
+    options: &AssemblerOptions,
+    frame: &Frame,
+    m_records: &mut Vec<Frame>,
+) {
+    let symbols = match kind {
+        ExpressionType::Absolute => vec![],
+        ExpressionType::Relative if options.absolute => vec![],
+        ExpressionType::Relative => vec![String::new()],
+        ExpressionType::External(externals) => externals.iter().map(|e| e.to_string()).collect(),
+    };
+
+    for symbol in symbols {
+        m_records.push(Frame::from(
+            FrameInner::ObjectRecord(ObjectRecord::Modification(ModificationRecord {
+                start,
+                length,
+                symbol,
+            })),
+            None,
+            frame,
+        ));
+    }
+}
+
+/// Encodes a format 3 instruction with a 12-bit displacement.
+fn format3(opcode: u8, nixbpe: u8, disp: i32) -> Vec<u8> {
+    vec![
+        opcode | (nixbpe & 0b110000) >> 4,
+        nixbpe << 4 | ((disp & 0b111100000000) >> 8) as u8,
+        disp as u8,
+    ]
+}
+
 #[cfg(test)]
 mod tests {
     use crate::frameformer::{
-        block::rearrange_blocks, literal::dump_literals, section::split_into_sections,
-        symbol::resolve_symbols,
+        literal::dump_literals, section::split_into_sections, symbol::resolve_symbols,
     };
 
     use super::*;
@@ -477,10 +434,9 @@ mod tests {
 
         let programs = split_into_sections(frames);
         let first = programs[0].clone();
-        let frames = rearrange_blocks(first);
-        let frames = dump_literals(frames);
-        let frames = resolve_symbols(frames).unwrap();
-        let records = translate_to_record(frames).unwrap();
+        let frames = dump_literals(first, &AssemblerOptions::default()).unwrap();
+        let (frames, blocks) = resolve_symbols(frames, &AssemblerOptions::default()).unwrap();
+        let records = translate_to_record(frames, &blocks, &AssemblerOptions::default()).unwrap();
         dbg!(&records);
         for record in &records {
             println!("{}", record);
@@ -488,4 +444,41 @@ mod tests {
 
         assert_eq!(records.len(), 45);
     }
+
+    #[test]
+    fn translate_format1() {
+        let source = "PROG\tSTART\t0\n\tFIX\n\tFLOAT\n\tHIO\n\tNORM\n\tSIO\n\tTIO\n\tEND\tPROG";
+        let mut frames = Vec::<Frame>::new();
+        for (i, line) in source.lines().enumerate() {
+            let frame = Frame::from_source(line, i as u32 + 1).unwrap();
+            if let Some(frame) = frame {
+                frames.push(frame);
+            }
+        }
+
+        let programs = split_into_sections(frames);
+        let first = programs[0].clone();
+        let frames = dump_literals(first, &AssemblerOptions::default()).unwrap();
+        let (frames, blocks) = resolve_symbols(frames, &AssemblerOptions::default()).unwrap();
+        let records = translate_to_record(frames, &blocks, &AssemblerOptions::default()).unwrap();
+
+        let texts = records
+            .iter()
+            .filter_map(|r| match r {
+                ObjectRecord::Text(t) => Some((t.start, t.data.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            vec![
+                (0, vec![0xC4]),
+                (1, vec![0xC0]),
+                (2, vec![0xF4]),
+                (3, vec![0xC8]),
+                (4, vec![0xF0]),
+                (5, vec![0xF8]),
+            ]
+        );
+    }
 }
@@ -1,3 +1,4 @@
 pub mod assembler;
 pub mod frame;
 pub mod frameformer;
+pub mod preprocess;
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use super::{has_label, SourceLine};
+use crate::frame::charset::Charset;
+use crate::frame::expression::{parse_with, ExpressionType};
+use crate::frame::instruction::register_number;
+
+/// The values known at assembly time: `SET` variables, which can be redefined,
+/// the `EQU` constants defined so far with absolute values and the predefined symbols.
+#[derive(Debug, Default)]
+pub struct AssemblySymbols {
+    pub variables: HashMap<String, i32>,
+    pub constants: HashMap<String, i32>,
+    /// The symbols predefined by the options, which only `SET` can redefine.
+    pub predefined: HashMap<String, i32>,
+    /// The character set `C'...'` constants are encoded in.
+    pub charset: Charset,
+}
+
+/// An `IF` block being processed.
+#[derive(Debug)]
+pub struct Conditional {
+    /// The `IF` line, for unbalanced blocks.
+    pub line: SourceLine,
+    /// Whether the lines of the current branch are assembled.
+    pub active: bool,
+    /// Whether the enclosing lines are assembled.
+    pub enclosing: bool,
+    /// Whether the `IF` branch was taken.
+    pub taken: bool,
+    pub has_else: bool,
+}
+
+impl AssemblySymbols {
+    /// Evaluates an absolute expression using the assembly-time values.
+    pub fn evaluate(&self, expr: &str) -> Result<i32, String> {
+        let mut value = parse_with(expr, self.charset)?;
+        value.substitute(|symbol| {
+            self.variables
+                .get(symbol)
+                .or(self.constants.get(symbol))
+                .or(self.predefined.get(symbol))
+                .map(|value| (*value, ExpressionType::Absolute))
+        });
+
+        match value.resolve()? {
+            Some((value, ExpressionType::Absolute)) => Ok(value),
+            Some(_) => Err(format!(
+                "Assembly-time expression \"{}\" must be absolute",
+                expr
+            )),
+            None => Err(format!("Cannot evaluate \"{}\" at assembly time", expr)),
+        }
+    }
+
+    /// Evaluates an `IF` condition, either an expression that is true when not zero,
+    /// or two expressions compared by `=`, `<>`, `<`, `<=`, `>` or `>=`.
+    pub fn condition(&self, operand: &str) -> Result<bool, String> {
+        for op in ["<>", "<=", ">=", "=", "<", ">"] {
+            let Some((left, right)) = operand.split_once(op) else {
+                continue;
+            };
+            let (left, right) = (self.evaluate(left)?, self.evaluate(right)?);
+            return Ok(match op {
+                "<>" => left != right,
+                "<=" => left <= right,
+                ">=" => left >= right,
+                "=" => left == right,
+                "<" => left < right,
+                _ => left > right,
+            });
+        }
+
+        Ok(self.evaluate(operand)? != 0)
+    }
+
+    /// Records the value of an `EQU` if it is known at assembly time,
+    /// symbols defined relative to the location counter are left to the assembler.
+    pub fn define_constant(&mut self, name: &str, expr: &str) -> Result<(), String> {
+        if self.variables.contains_key(name) {
+            return Err(format!("Symbol {} is already defined by SET", name));
+        }
+        if self.predefined.contains_key(name) {
+            return Err(format!(
+                "Symbol {} is predefined and cannot be redefined",
+                name
+            ));
+        }
+        if let Ok(value) = self.evaluate(expr) {
+            self.constants.insert(name.to_string(), value);
+        }
+        Ok(())
+    }
+
+    /// Assigns a `SET` variable, which cannot be named like a register.
+    pub fn set(&mut self, name: &str, expr: &str) -> Result<(), String> {
+        if register_number(name).is_some() {
+            return Err(format!("Register {} cannot be assigned by SET", name));
+        }
+        if self.constants.contains_key(name) {
+            return Err(format!("Symbol {} is already defined by EQU", name));
+        }
+        let value = self.evaluate(expr)?;
+        self.variables.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// Replaces the `SET` variables in the operand field of the line with their values.
+    pub fn substitute(&self, text: &str) -> String {
+        if self.variables.is_empty() {
+            return text.to_string();
+        }
+        let Some((start, end)) = operand_span(text) else {
+            return text.to_string();
+        };
+
+        let operand = &text[start..end];
+        let mut result = String::new();
+        let mut chars = operand.char_indices().peekable();
+        let mut quoted = false;
+        while let Some((i, c)) = chars.next() {
+            if quoted || c == '\'' || !is_symbol_char(c) {
+                if c == '\'' {
+                    quoted = !quoted;
+                }
+                result.push(c);
+                continue;
+            }
+
+            let mut end = i + c.len_utf8();
+            while let Some((j, c)) = chars.peek().copied().filter(|(_, c)| is_symbol_char(*c)) {
+                end = j + c.len_utf8();
+                chars.next();
+            }
+            let word = &operand[i..end];
+
+            // numbers, the prefixes of constants like X'05' and registers,
+            // e.g. the index of BUF,X or the operands of CLEAR A, are kept
+            let constant = c.is_ascii_digit()
+                || chars.peek().is_some_and(|(_, c)| *c == '\'')
+                || register_number(word).is_some();
+            match self.variables.get(word) {
+                Some(value) if !constant && *value < 0 => result.push_str(&format!("({value})")),
+                Some(value) if !constant => result.push_str(&value.to_string()),
+                _ => result.push_str(word),
+            }
+        }
+
+        format!("{}{}{}", &text[..start], result, &text[end..])
+    }
+}
+
+/// Finds the operand field of a line: the third field, or the second one
+/// when there are only two or the line starts without a label.
+fn operand_span(text: &str) -> Option<(usize, usize)> {
+    let mut fields = vec![];
+    let mut start = None;
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        if c == '\'' {
+            quoted = !quoted;
+        }
+        if !quoted && (c == '.' || c.is_whitespace()) {
+            if let Some(s) = start.take() {
+                fields.push((s, i));
+            }
+            if c == '.' {
+                break;
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start.filter(|_| !quoted) {
+        fields.push((s, text.len()));
+    }
+
+    match fields.len() {
+        2 => Some(fields[1]),
+        3.. if !has_label(text) => Some(fields[1]),
+        3.. => Some(fields[2]),
+        _ => None,
+    }
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '?'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditions() {
+        let mut symbols = AssemblySymbols::default();
+        symbols.define_constant("MODE", "2").unwrap();
+        symbols.define_constant("START", "*").unwrap();
+        symbols.set("N", "MODE*3").unwrap();
+
+        assert_eq!(symbols.evaluate("N+1"), Ok(7));
+        assert_eq!(symbols.condition("MODE=2"), Ok(true));
+        assert_eq!(symbols.condition("N<>6"), Ok(false));
+        assert_eq!(symbols.condition("MODE-2"), Ok(false));
+        assert_eq!(
+            symbols.condition("START"),
+            Err("Cannot evaluate \"START\" at assembly time".to_string())
+        );
+        assert!(symbols.set("MODE", "1").is_err());
+        assert!(symbols.define_constant("N", "1").is_err());
+    }
+
+    #[test]
+    fn substitute_variables() {
+        let mut symbols = AssemblySymbols::default();
+        symbols.set("N", "5").unwrap();
+        symbols.set("D", "-1").unwrap();
+
+        assert_eq!(symbols.substitute("N\tWORD\tN+D,NN"), "N\tWORD\t5+(-1),NN");
+        assert_eq!(symbols.substitute("\tLDA\t#N .N"), "\tLDA\t#5 .N");
+        assert_eq!(
+            symbols.substitute("\tBYTE\tX'0N',C'N'"),
+            "\tBYTE\tX'0N',C'N'"
+        );
+        assert_eq!(symbols.substitute("\tRSUB"), "\tRSUB");
+
+        symbols.variables.insert("X".to_string(), 2);
+        assert_eq!(symbols.substitute("\tLDA\tN,X"), "\tLDA\t5,X");
+        assert_eq!(
+            symbols.set("A", "1"),
+            Err("Register A cannot be assigned by SET".to_string())
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{has_label, SourceLine};
+use crate::assembler::AssemblerOptions;
+use crate::frame::tokenize::tokenize;
+use crate::frame::Location;
+
+/// Splits the source into lines located in the file, `parents` are the locations
+/// of the `INCLUDE` lines it was included from.
+pub fn split_lines(source: &str, file: Option<&Path>, parents: &[Location]) -> Vec<SourceLine> {
+    let file_name = file.map(|f| f.display().to_string());
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| {
+            let mut location = vec![Location::new(file_name.clone(), i as u32 + 1)];
+            location.extend_from_slice(parents);
+            SourceLine {
+                text: text.to_string(),
+                location,
+            }
+        })
+        .collect()
+}
+
+/// The files being included, to look up `INCLUDE` files and detect cycles.
+#[derive(Debug, Default)]
+pub struct Includes {
+    stack: Vec<PathBuf>,
+    include_paths: Vec<PathBuf>,
+}
+
+impl Includes {
+    /// Starts including from the source file, or from a source given as a string.
+    pub fn new(file: Option<&Path>, options: &AssemblerOptions) -> Includes {
+        Includes {
+            stack: file
+                .map(|file| fs::canonicalize(file).unwrap_or(file.to_path_buf()))
+                .into_iter()
+                .collect(),
+            include_paths: options.include_paths.clone(),
+        }
+    }
+
+    /// Reads the lines of the file included by the line, which stays open until [`Includes::leave`].
+    /// The file is looked up relative to the file the line is written in, or the current
+    /// directory for the source given as a string, and then in the include paths.
+    pub fn enter(&mut self, name: &str, line: &SourceLine) -> Result<Vec<SourceLine>, String> {
+        let located = |e: String| format!("{}\n\tat {}", e, line.source());
+        let dir = line.location[0]
+            .file
+            .as_deref()
+            .and_then(|file| Path::new(file).parent())
+            .unwrap_or(Path::new(""));
+        let path = search(name, dir, &self.include_paths)
+            .ok_or_else(|| located(format!("Cannot find include file \"{}\"", name)))?;
+        let canonical = fs::canonicalize(&path).unwrap_or(path.clone());
+        if self.stack.contains(&canonical) {
+            return Err(located(format!("Circular include of \"{}\"", name)));
+        }
+        let included = fs::read_to_string(&path).map_err(|e| {
+            located(format!(
+                "Failed to read include file \"{}\": {}",
+                path.display(),
+                e
+            ))
+        })?;
+
+        self.stack.push(canonical);
+        Ok(split_lines(&included, Some(&path), &line.location))
+    }
+
+    /// Closes the file entered last.
+    pub fn leave(&mut self) {
+        self.stack.pop();
+    }
+}
+
+/// Returns the file name if the line is an INCLUDE directive.
+/// Lines that fail to tokenize are left to the parser to report.
+/// A line starting in the first column with `INCLUDE` and an unquoted operand,
+/// e.g. `INCLUDE RSUB`, defines a label named INCLUDE instead.
+pub(super) fn include_operand(text: &str) -> Result<Option<String>, String> {
+    let Ok(tokens) = tokenize(text) else {
+        return Ok(None);
+    };
+
+    let labeled = has_label(text);
+    match tokens.iter().map(|t| t.as_str()).collect::<Vec<_>>()[..] {
+        ["INCLUDE"] => Err("Missing operand".to_string()),
+        ["INCLUDE", name] if !labeled || name.starts_with('\'') => {
+            let name = name
+                .strip_prefix('\'')
+                .and_then(|name| name.strip_suffix('\''))
+                .unwrap_or(name);
+            Ok(Some(name.to_string()))
+        }
+        [_, "INCLUDE", ..] if labeled => Err("INCLUDE cannot have a label".to_string()),
+        _ => Ok(None),
+    }
+}
+
+/// Looks up the file in the directory of the including file, then in the include paths.
+fn search(name: &str, dir: &Path, include_paths: &[PathBuf]) -> Option<PathBuf> {
+    std::iter::once(dir)
+        .chain(include_paths.iter().map(|p| p.as_path()))
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::conditional::{AssemblySymbols, Conditional};
+use super::include::{include_operand, Includes};
+use super::repeat::{repetition_body, Repetition, MAX_COUNT};
+use super::{has_label, SourceLine};
+use crate::assembler::AssemblerOptions;
+use crate::frame::directive::split_list;
+use crate::frame::tokenize::tokenize;
+
+/// The deepest macro calls may be nested, to stop runaway recursion.
+const MAX_DEPTH: usize = 64;
+
+/// A macro defined by `NAME MACRO &P1,&P2=default` ... `MEND`.
+#[derive(Debug, Clone)]
+struct Macro {
+    /// The parameters without the leading `&`, keyword parameters have a default value.
+    params: Vec<(String, Option<String>)>,
+    body: Vec<SourceLine>,
+}
+
+#[derive(Debug, Default)]
+struct MacroProcessor {
+    macros: HashMap<String, Macro>,
+    /// The number of expansions so far, numbering `&SYSNDX` and `$` labels.
+    expansions: u32,
+    /// The iterations of the repetitions being expanded multiplied together.
+    iterations: usize,
+    symbols: AssemblySymbols,
+    includes: Includes,
+}
+
+/// Expands the macro calls, removing the macro definitions, and evaluates the
+/// conditional assembly directives (`IF`/`ELSE`/`ENDIF` and `SET`), repetitions
+/// (`REPT`/`IRP` ... `ENDR`) and `INCLUDE` directives, skipping those in inactive branches.
+/// A macro must be defined before it is called, and calls in a macro body
+/// are expanded when the body is, so do definitions and conditions.
+pub fn expand(lines: Vec<SourceLine>) -> Result<Vec<SourceLine>, String> {
+    expand_with(lines, &AssemblerOptions::default())
+}
+
+/// Expands the lines like [`expand`], with the predefined symbols of the options
+/// available to conditions.
+pub fn expand_with(
+    lines: Vec<SourceLine>,
+    options: &AssemblerOptions,
+) -> Result<Vec<SourceLine>, String> {
+    expand_file(lines, None, options)
+}
+
+/// Expands the lines of the source file like [`expand_with`],
+/// `INCLUDE` files are looked up relative to it.
+pub fn expand_file(
+    lines: Vec<SourceLine>,
+    file: Option<&Path>,
+    options: &AssemblerOptions,
+) -> Result<Vec<SourceLine>, String> {
+    let mut processor = MacroProcessor {
+        iterations: 1,
+        includes: Includes::new(file, options),
+        ..Default::default()
+    };
+    processor.symbols.predefined = options.predefined.iter().cloned().collect();
+    processor.symbols.charset = options.charset;
+    let mut output = vec![];
+    processor.process(lines, 0, &mut output)?;
+    Ok(output)
+}
+
+impl MacroProcessor {
+    fn process(
+        &mut self,
+        lines: Vec<SourceLine>,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) -> Result<(), String> {
+        let mut conditionals = vec![];
+        self.process_lines(lines, depth, &mut conditionals, output)?;
+
+        match conditionals.pop() {
+            Some(conditional) => Err(format!(
+                "IF without ENDIF\n\tat {}",
+                conditional.line.source()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Processes the lines within the open conditionals,
+    /// which are shared with the files the lines include.
+    fn process_lines(
+        &mut self,
+        lines: Vec<SourceLine>,
+        depth: usize,
+        conditionals: &mut Vec<Conditional>,
+        output: &mut Vec<SourceLine>,
+    ) -> Result<(), String> {
+        let mut lines = lines.into_iter();
+        while let Some(line) = lines.next() {
+            let active = conditionals.last().is_none_or(|c| c.active);
+            // lines that fail to tokenize are left to the parser to report
+            let Ok(tokens) = tokenize(&line.text) else {
+                if active {
+                    output.push(line);
+                }
+                continue;
+            };
+            let located = |e: String| format!("{}\n\tat {}", e, line.source());
+
+            match tokens.iter().map(|t| t.as_str()).collect::<Vec<_>>()[..] {
+                ["IF", condition] => {
+                    let taken = active && self.symbols.condition(condition).map_err(&located)?;
+                    conditionals.push(Conditional {
+                        line: line.clone(),
+                        active: taken,
+                        enclosing: active,
+                        taken,
+                        has_else: false,
+                    });
+                }
+                ["IF"] => return Err(located("Missing operand".to_string())),
+                ["ELSE"] => {
+                    let conditional = conditionals
+                        .last_mut()
+                        .filter(|c| !c.has_else)
+                        .ok_or_else(|| located("ELSE without IF".to_string()))?;
+                    conditional.active = conditional.enclosing && !conditional.taken;
+                    conditional.has_else = true;
+                }
+                ["ENDIF"] => {
+                    conditionals
+                        .pop()
+                        .ok_or_else(|| located("ENDIF without IF".to_string()))?;
+                }
+                _ if !active => {}
+                ["MACRO", ..] => return Err(located("Missing macro name".to_string())),
+                [name, "MACRO"] | [name, "MACRO", _] => {
+                    let params = parse_params(tokens.get(2)).map_err(&located)?;
+                    let body = definition_body(&mut lines)
+                        .ok_or_else(|| located(format!("Macro {} is not closed by MEND", name)))?;
+                    self.macros.insert(name.to_string(), Macro { params, body });
+                }
+                ["MEND"] => return Err(located("MEND without MACRO".to_string())),
+                [directive @ ("REPT" | "IRP"), operand] if !has_label(&line.text) => {
+                    let repetition = match directive {
+                        "REPT" => Repetition::rept(operand, &self.symbols),
+                        _ => Repetition::irp(operand),
+                    }
+                    .map_err(&located)?;
+                    let body = repetition_body(&mut lines)
+                        .ok_or_else(|| located(format!("{} is not closed by ENDR", directive)))?;
+                    if depth >= MAX_DEPTH {
+                        return Err(located("Repetitions nested too deeply".to_string()));
+                    }
+                    let iterations = self.iterations.saturating_mul(repetition.count());
+                    if iterations > MAX_COUNT as usize {
+                        return Err(located(format!(
+                            "Nested repetitions exceed the maximum of {} iterations",
+                            MAX_COUNT
+                        )));
+                    }
+                    let enclosing = std::mem::replace(&mut self.iterations, iterations);
+                    for iteration in repetition.iterations(&body, directive, &line) {
+                        self.process(iteration, depth + 1, output)?;
+                    }
+                    self.iterations = enclosing;
+                }
+                ["REPT" | "IRP"] => return Err(located("Missing operand".to_string())),
+                [_, directive @ ("REPT" | "IRP"), ..] if has_label(&line.text) => {
+                    return Err(located(format!("{} cannot have a label", directive)))
+                }
+                ["ENDR"] => return Err(located("ENDR without REPT or IRP".to_string())),
+                ["SET", ..] => return Err(located("Missing label".to_string())),
+                [_, "SET"] => return Err(located("Missing operand".to_string())),
+                [name, "SET", value] => self.symbols.set(name, value).map_err(&located)?,
+                _ => {
+                    if let Some(name) = include_operand(&line.text).map_err(&located)? {
+                        let included = self.includes.enter(&name, &line)?;
+                        self.process_lines(included, depth, conditionals, output)?;
+                        self.includes.leave();
+                        continue;
+                    }
+
+                    let line = SourceLine {
+                        text: self.symbols.substitute(&line.text),
+                        location: line.location.clone(),
+                    };
+                    let tokens = tokenize(&line.text).unwrap_or_default();
+                    if let [name, op, value] = &tokens[..] {
+                        if op == "EQU" {
+                            self.symbols
+                                .define_constant(name, value)
+                                .map_err(&located)?;
+                        }
+                    }
+
+                    match self.call(&line.text, &tokens) {
+                        Some((label, name, args)) => {
+                            if depth >= MAX_DEPTH {
+                                return Err(located("Macro calls nested too deeply".to_string()));
+                            }
+                            let expanded =
+                                self.expansion(label, name, args, &line).map_err(&located)?;
+                            self.process(expanded, depth + 1, output)?;
+                        }
+                        None => output.push(line),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the label, the macro name and the arguments if the line calls a macro.
+    /// A line starting in the first column has a label, even if it is named like a macro.
+    fn call<'a>(
+        &self,
+        text: &str,
+        tokens: &'a [String],
+    ) -> Option<(Option<&'a str>, &'a str, &'a str)> {
+        let args = |i: usize| tokens.get(i).map(|a| a.as_str()).unwrap_or("");
+        match tokens {
+            [label, name, ..] if has_label(text) && self.macros.contains_key(name) => {
+                Some((Some(label.as_str()), name.as_str(), args(2)))
+            }
+            [name, ..] if !has_label(text) && self.macros.contains_key(name) => {
+                Some((None, name.as_str(), args(1)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Expands a call into the lines of the macro body, with the arguments substituted.
+    /// The label of the call is kept by an `EQU *` line before the body.
+    fn expansion(
+        &mut self,
+        label: Option<&str>,
+        name: &str,
+        args: &str,
+        call: &SourceLine,
+    ) -> Result<Vec<SourceLine>, String> {
+        let definition = self.macros[name].clone();
+        let values = bind_args(name, &definition.params, args)?;
+
+        self.expansions += 1;
+        let sysndx = format!("{:04}", self.expansions);
+        let unique = unique_prefix(self.expansions);
+
+        let mut site = call.location.clone();
+        site[0].expanding = Some(name.to_string());
+
+        let mut lines = vec![];
+        if let Some(label) = label {
+            lines.push(SourceLine {
+                text: format!("{}\tEQU\t*", label),
+                location: call.location.clone(),
+            });
+        }
+
+        // definitions in the body get their unique labels when they are expanded
+        let mut nested = 0;
+        for line in &definition.body {
+            let operator = tokenize(&line.text).ok().and_then(|t| t.get(1).cloned());
+            if operator.as_deref() == Some("MACRO") {
+                nested += 1;
+            }
+
+            let text = if nested == 0 {
+                substitute(&line.text, &values, Some((&sysndx, &unique)))
+            } else {
+                substitute(&line.text, &values, None)
+            };
+
+            if tokenize(&line.text).is_ok_and(|t| t == ["MEND"]) {
+                nested -= 1;
+            }
+
+            let mut location = vec![line.location[0].clone()];
+            location.extend_from_slice(&site);
+            lines.push(SourceLine { text, location });
+        }
+
+        Ok(lines)
+    }
+}
+
+/// Parses the parameter list of a macro definition, e.g. `&INDEV,&BUFADR=BUFFER`.
+fn parse_params(list: Option<&String>) -> Result<Vec<(String, Option<String>)>, String> {
+    let Some(list) = list else {
+        return Ok(vec![]);
+    };
+
+    let mut params: Vec<(String, Option<String>)> = vec![];
+    for param in split_list(list) {
+        let (name, default) = match param.split_once('=') {
+            Some((name, default)) => (name, Some(default.to_string())),
+            None => (param, None),
+        };
+        let name = name
+            .strip_prefix('&')
+            .filter(|name| !name.is_empty() && name.chars().all(is_name_char))
+            .ok_or(format!("Invalid macro parameter \"{}\"", param))?;
+        if params.iter().any(|(other, _)| other == name) {
+            return Err(format!("Duplicate macro parameter \"{}\"", param));
+        }
+        params.push((name.to_string(), default));
+    }
+
+    Ok(params)
+}
+
+/// Takes the lines up to the `MEND` closing the definition, nested definitions included.
+fn definition_body(lines: &mut impl Iterator<Item = SourceLine>) -> Option<Vec<SourceLine>> {
+    let mut body = vec![];
+    let mut nested = 0;
+    for line in lines {
+        let tokens = tokenize(&line.text).unwrap_or_default();
+        if tokens.get(1).is_some_and(|t| t == "MACRO") {
+            nested += 1;
+        } else if tokens == ["MEND"] {
+            if nested == 0 {
+                return Some(body);
+            }
+            nested -= 1;
+        }
+        body.push(line);
+    }
+    None
+}
+
+/// Binds the arguments of a call to the parameters.
+/// Positional arguments are taken in order, `&NAME=value` sets a parameter by name,
+/// omitted parameters are empty unless they have a default.
+fn bind_args(
+    name: &str,
+    params: &[(String, Option<String>)],
+    args: &str,
+) -> Result<HashMap<String, String>, String> {
+    let mut values = params
+        .iter()
+        .map(|(param, default)| (param.clone(), default.clone().unwrap_or_default()))
+        .collect::<HashMap<_, _>>();
+    if args.is_empty() {
+        return Ok(values);
+    }
+
+    let mut position = 0;
+    for arg in split_list(args) {
+        if let Some((param, value)) = arg.strip_prefix('&').and_then(|a| a.split_once('=')) {
+            if !values.contains_key(param) {
+                return Err(format!("Macro {} has no parameter \"&{}\"", name, param));
+            }
+            values.insert(param.to_string(), value.to_string());
+        } else {
+            let (param, _) = params
+                .get(position)
+                .ok_or(format!("Too many arguments to macro {}", name))?;
+            values.insert(param.clone(), arg.to_string());
+            position += 1;
+        }
+    }
+
+    Ok(values)
+}
+
+/// Replaces `&NAME` with the parameter values, `->` after a parameter joins it with the
+/// following text. When `unique` is given, `&SYSNDX` becomes the expansion number
+/// and labels starting with `$` get the unique prefix of the expansion.
+pub(super) fn substitute(
+    text: &str,
+    values: &HashMap<String, String>,
+    unique: Option<(&str, &str)>,
+) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars().peekable();
+    let mut quoted = false;
+    let mut prev = ' ';
+
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek().filter(|c| is_name_char(**c)) {
+                    name.push(c);
+                    chars.next();
+                }
+
+                let value = match (values.get(&name), unique) {
+                    (Some(value), _) => Some(value.as_str()),
+                    (None, Some((sysndx, _))) if name == "SYSNDX" => Some(sysndx),
+                    _ => None,
+                };
+                match value {
+                    Some(value) => {
+                        result.push_str(value);
+                        let mut lookahead = chars.clone();
+                        if lookahead.next() == Some('-') && lookahead.next() == Some('>') {
+                            chars.next();
+                            chars.next();
+                        }
+                    }
+                    None => {
+                        result.push('&');
+                        result.push_str(&name);
+                    }
+                }
+                prev = 'A';
+                continue;
+            }
+            '\'' => quoted = !quoted,
+            '$' if !quoted && !is_name_char(prev) => {
+                if let Some((_, prefix)) = unique {
+                    result.push_str(prefix);
+                    prev = c;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        result.push(c);
+        prev = c;
+    }
+
+    result
+}
+
+/// The prefix of `$` labels in the given expansion: `$AA`, `$AB`, ... `$ZZ`, `$BAA`, ...
+fn unique_prefix(expansion: u32) -> String {
+    let mut n = expansion - 1;
+    let mut letters = vec![];
+    while n > 0 || letters.len() < 2 {
+        letters.push((b'A' + (n % 26) as u8) as char);
+        n /= 26;
+    }
+    letters.push('$');
+    letters.iter().rev().collect()
+}
+
+pub(super) fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lines;
+    use super::*;
+
+    fn texts(lines: Vec<SourceLine>) -> Vec<String> {
+        lines.into_iter().map(|l| l.text).collect()
+    }
+
+    #[test]
+    fn parameters() {
+        let source = "RDBUFF\tMACRO\t&INDEV,&BUFADR,&EOR=04\n\
+                      $LOOP\tTD\t=X'&INDEV'\n\
+                      \tCOMP\t#&EOR\n\
+                      \tJEQ\t$LOOP\n\
+                      \tSTCH\t&BUFADR->X\n\
+                      \tMEND\n\
+                      FIRST\tRDBUFF\tF1,BUF\n\
+                      \tRDBUFF\t05,BUF,&EOR=00";
+        let result = texts(expand(lines(source)).unwrap());
+        assert_eq!(
+            result,
+            vec![
+                "FIRST\tEQU\t*",
+                "$AALOOP\tTD\t=X'F1'",
+                "\tCOMP\t#04",
+                "\tJEQ\t$AALOOP",
+                "\tSTCH\tBUFX",
+                "$ABLOOP\tTD\t=X'05'",
+                "\tCOMP\t#00",
+                "\tJEQ\t$ABLOOP",
+                "\tSTCH\tBUFX",
+            ]
+        );
+
+        // a label named like a macro is not a call
+        let source = "WAIT\tMACRO\n\tTD\tDEV\n\tMEND\nWAIT\tRSUB\n\tJ\tWAIT";
+        let result = texts(expand(lines(source)).unwrap());
+        assert_eq!(result, vec!["WAIT\tRSUB", "\tJ\tWAIT"]);
+    }
+
+    #[test]
+    fn nested() {
+        let source = "OUTER\tMACRO\t&NAME\n\
+                      &NAME\tMACRO\t&X\n\
+                      L&SYSNDX\tLDA\t&X\n\
+                      \tMEND\n\
+                      \tMEND\n\
+                      INNER\tMACRO\n\
+                      \tOUTER\tLOADER\n\
+                      \tMEND\n\
+                      \tINNER\n\
+                      \tLOADER\tA\n\
+                      \tLOADER\tB";
+        let result = texts(expand(lines(source)).unwrap());
+        assert_eq!(result, vec!["L0003\tLDA\tA", "L0004\tLDA\tB"]);
+    }
+
+    #[test]
+    fn conditions() {
+        let source = "M\tMACRO\t&A\n\
+                      \tIF\t&A=1\n\
+                      \tLDA\tONE\n\
+                      \tELSE\n\
+                      \tLDA\tOTHER\n\
+                      \tENDIF\n\
+                      \tMEND\n\
+                      \tM\t1\n\
+                      \tM\t2";
+        let result = texts(expand(lines(source)).unwrap());
+        assert_eq!(result, vec!["\tLDA\tONE", "\tLDA\tOTHER"]);
+
+        let source = "M\tMACRO\n\tIF\t1\n\tMEND\n\tM";
+        let err = expand(lines(source)).unwrap_err();
+        assert!(
+            err.starts_with("IF without ENDIF\n\tat Source \tIF\t1 (Line 2)\n\texpanded from M")
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = expand(lines("M\tMACRO\t&A\n\tLDA\t&A")).unwrap_err();
+        assert_eq!(
+            err,
+            "Macro M is not closed by MEND\n\tat Source M\tMACRO\t&A (Line 1)"
+        );
+
+        let err = expand(lines("\tMEND")).unwrap_err();
+        assert!(err.starts_with("MEND without MACRO"));
+
+        let err = expand(lines("M\tMACRO\t&A,B\n\tMEND")).unwrap_err();
+        assert!(err.starts_with("Invalid macro parameter \"B\""));
+
+        let err = expand(lines("M\tMACRO\t&A\n\tMEND\n\tM\t1,2")).unwrap_err();
+        assert!(err.starts_with("Too many arguments to macro M"));
+
+        let err = expand(lines("M\tMACRO\n\tM\n\tMEND\n\tM")).unwrap_err();
+        assert!(err.starts_with("Macro calls nested too deeply"));
+    }
+}
@@ -0,0 +1,47 @@
+pub mod conditional;
+pub mod include;
+pub mod macros;
+pub mod repeat;
+
+use std::path::Path;
+
+use crate::assembler::AssemblerOptions;
+use crate::frame::{FrameSource, Location};
+
+/// A line of source code and where it was found,
+/// the innermost location first followed by the lines it was included or expanded from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub text: String,
+    pub location: Vec<Location>,
+}
+
+impl SourceLine {
+    /// The frame source of the line, for error messages.
+    pub fn source(&self) -> FrameSource {
+        FrameSource::Source(self.text.clone(), self.location.clone())
+    }
+}
+
+/// Whether the line starts with a label, i.e. its first column is not blank.
+pub fn has_label(text: &str) -> bool {
+    !text.is_empty() && !text.starts_with(char::is_whitespace)
+}
+
+/// Reads the source into lines ready to be parsed into frames,
+/// with the `INCLUDE` files inserted, the macros and repetitions expanded
+/// and the conditions evaluated.
+pub fn read_source(
+    source: &str,
+    file: Option<&Path>,
+    options: &AssemblerOptions,
+) -> Result<Vec<SourceLine>, String> {
+    let lines = include::split_lines(source, file, &[]);
+    macros::expand_file(lines, file, options)
+}
+
+/// Splits the source into lines located at their line numbers, for tests.
+#[cfg(test)]
+fn lines(source: &str) -> Vec<SourceLine> {
+    include::split_lines(source, None, &[])
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use super::conditional::AssemblySymbols;
+use super::macros::{is_name_char, substitute};
+use super::{has_label, SourceLine};
+use crate::frame::directive::split_list;
+use crate::frame::tokenize::tokenize;
+
+/// The most times a `REPT` block may be repeated, and the most iterations nested
+/// repetitions may take together, to stop runaway repetitions.
+pub(super) const MAX_COUNT: i32 = 65536;
+
+/// A `REPT` or `IRP` block, whose body is assembled once for each value of the variable.
+#[derive(Debug)]
+pub struct Repetition {
+    /// The variable without the leading `&`, optional for `REPT`.
+    pub variable: Option<String>,
+    pub values: RepetitionValues,
+}
+
+/// The values a repetition variable takes.
+#[derive(Debug)]
+pub enum RepetitionValues {
+    /// 0 to `count - 1` for `REPT`, generated as the iterations are expanded.
+    Count(i32),
+    /// The list of `IRP`.
+    List(Vec<String>),
+}
+
+impl Repetition {
+    /// Parses `REPT count[,&VAR]`, the variable counts from 0 to `count - 1`.
+    pub fn rept(operand: &str, symbols: &AssemblySymbols) -> Result<Repetition, String> {
+        let (count, variable) = match operand.split_once(',') {
+            Some((count, variable)) => (count, Some(parse_variable(variable)?)),
+            None => (operand, None),
+        };
+
+        let count = symbols.evaluate(count)?;
+        if count < 0 {
+            return Err(format!("REPT count {} cannot be negative", count));
+        }
+        if count > MAX_COUNT {
+            return Err(format!(
+                "REPT count {} exceeds the maximum of {}",
+                count, MAX_COUNT
+            ));
+        }
+
+        Ok(Repetition {
+            variable,
+            values: RepetitionValues::Count(count),
+        })
+    }
+
+    /// Parses `IRP &VAR,<a,b,c>`, the variable takes each value of the list in turn.
+    pub fn irp(operand: &str) -> Result<Repetition, String> {
+        let (variable, list) = operand
+            .split_once(',')
+            .ok_or("IRP needs a variable and a list of values".to_string())?;
+        let variable = Some(parse_variable(variable)?);
+
+        let list = list
+            .strip_prefix('<')
+            .and_then(|list| list.strip_suffix('>'))
+            .unwrap_or(list);
+        let values = match list {
+            "" => vec![],
+            list => split_list(list).into_iter().map(str::to_string).collect(),
+        };
+
+        Ok(Repetition {
+            variable,
+            values: RepetitionValues::List(values),
+        })
+    }
+
+    /// The number of iterations.
+    pub fn count(&self) -> usize {
+        match &self.values {
+            RepetitionValues::Count(count) => *count as usize,
+            RepetitionValues::List(values) => values.len(),
+        }
+    }
+
+    /// Expands the body once for each value, each iteration separately
+    /// so that its lines are located at the iteration of the `REPT` or `IRP` line.
+    /// The iterations are expanded one at a time as they are taken.
+    pub fn iterations<'a>(
+        &'a self,
+        body: &'a [SourceLine],
+        directive: &'a str,
+        site: &'a SourceLine,
+    ) -> impl Iterator<Item = Vec<SourceLine>> + 'a {
+        (0..self.count()).map(move |i| {
+            let value = match &self.values {
+                RepetitionValues::Count(_) => i.to_string(),
+                RepetitionValues::List(values) => values[i].clone(),
+            };
+            let mut location = site.location.clone();
+            location[0].expanding = Some(format!("{} iteration {}", directive, i + 1));
+
+            let values = self
+                .variable
+                .iter()
+                .map(|variable| (variable.clone(), value.clone()))
+                .collect::<HashMap<_, _>>();
+
+            body.iter()
+                .map(|line| {
+                    let mut line_location = vec![line.location[0].clone()];
+                    line_location.extend_from_slice(&location);
+                    SourceLine {
+                        text: substitute(&line.text, &values, None),
+                        location: line_location,
+                    }
+                })
+                .collect()
+        })
+    }
+}
+
+/// Takes the lines up to the `ENDR` closing the repetition, nested repetitions included.
+pub fn repetition_body(lines: &mut impl Iterator<Item = SourceLine>) -> Option<Vec<SourceLine>> {
+    let mut body = vec![];
+    let mut nested = 0;
+    for line in lines {
+        let tokens = tokenize(&line.text).unwrap_or_default();
+        let directive = tokens.first().map(|t| t.as_str());
+        if matches!(directive, Some("REPT" | "IRP")) && !has_label(&line.text) {
+            nested += 1;
+        } else if tokens == ["ENDR"] {
+            if nested == 0 {
+                return Some(body);
+            }
+            nested -= 1;
+        }
+        body.push(line);
+    }
+    None
+}
+
+fn parse_variable(variable: &str) -> Result<String, String> {
+    variable
+        .strip_prefix('&')
+        .filter(|name| !name.is_empty() && name.chars().all(is_name_char))
+        .map(str::to_string)
+        .ok_or(format!("Invalid repetition variable \"{}\"", variable))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lines;
+    use super::super::macros::expand;
+
+    #[test]
+    fn repetitions() {
+        let source = "N\tSET\t2\n\
+                      \tREPT\tN+1,&I\n\
+                      T&I\tWORD\t&I*3\n\
+                      \tENDR\n\
+                      \tIRP\t&R,<A,X>\n\
+                      \tREPT\t2\n\
+                      \tCLEAR\t&R\n\
+                      \tENDR\n\
+                      \tENDR\n\
+                      \tIRP\t&R,<>\n\
+                      \tCLEAR\t&R\n\
+                      \tENDR";
+        let result = expand(lines(source)).unwrap();
+        let texts = result.iter().map(|l| l.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            vec![
+                "T0\tWORD\t0*3",
+                "T1\tWORD\t1*3",
+                "T2\tWORD\t2*3",
+                "\tCLEAR\tA",
+                "\tCLEAR\tA",
+                "\tCLEAR\tX",
+                "\tCLEAR\tX",
+            ]
+        );
+        assert_eq!(
+            result[5].source().to_string(),
+            "Source \tCLEAR\tX (Line 7)\n\
+             \texpanded from REPT iteration 1 at Line 6\n\
+             \texpanded from IRP iteration 2 at Line 5"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = expand(lines("\tREPT\t2\n\tWORD\t0")).unwrap_err();
+        assert_eq!(
+            err,
+            "REPT is not closed by ENDR\n\tat Source \tREPT\t2 (Line 1)"
+        );
+
+        let err = expand(lines("\tENDR")).unwrap_err();
+        assert!(err.starts_with("ENDR without REPT or IRP"));
+
+        let err = expand(lines("\tREPT\t-1\n\tENDR")).unwrap_err();
+        assert!(err.starts_with("REPT count -1 cannot be negative"));
+
+        let err = expand(lines("\tREPT\t2000000000\n\tENDR")).unwrap_err();
+        assert_eq!(
+            err,
+            "REPT count 2000000000 exceeds the maximum of 65536\n\
+             \tat Source \tREPT\t2000000000 (Line 1)"
+        );
+
+        // the iterations of nested repetitions are limited together
+        let source = "\tREPT\t65536\n\tREPT\t65536\n\tENDR\n\tENDR";
+        let err = expand(lines(source)).unwrap_err();
+        assert_eq!(
+            err,
+            "Nested repetitions exceed the maximum of 65536 iterations\n\
+             \tat Source \tREPT\t65536 (Line 2)\n\
+             \texpanded from REPT iteration 1 at Line 1"
+        );
+        let source = "\tREPT\t32769\n\tIRP\t&X,<A,B>\n\tENDR\n\tENDR";
+        let err = expand(lines(source)).unwrap_err();
+        assert!(err.starts_with("Nested repetitions exceed the maximum of 65536 iterations"));
+        let source = "\tREPT\t256\n\tREPT\t256\n\tWORD\t0\n\tENDR\n\tENDR";
+        assert_eq!(expand(lines(source)).unwrap().len(), 65536);
+
+        let err = expand(lines("\tIRP\tX,<1,2>\n\tENDR")).unwrap_err();
+        assert!(err.starts_with("Invalid repetition variable \"X\""));
+        // REPT and IRP as symbols are left alone
+        let source = "\tJ\tREPT\nREPT\tRSUB\nIRP\tRESW\t1";
+        let result = expand(lines(source)).unwrap();
+        assert_eq!(result, lines(source));
+    }
+}